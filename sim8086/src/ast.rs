@@ -1,3 +1,4 @@
+/// Mod field of the mod reg r/m byte
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     Mem0Disp,
@@ -22,10 +23,13 @@ impl Mode {
 pub(crate) enum OperandType {
     Explicit,
     Implicit,
+    // intersegment indirect call or jump through a doubleword pointer in memory
+    Far,
 }
 
+/// Width of an operand or a memory access
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
-pub(crate) enum OperandSize {
+pub enum OperandSize {
     Byte,
     Word,
 }
@@ -37,8 +41,28 @@ impl OperandSize {
             Self::Word => 16,
         }
     }
+
+    // zero-extends the lower byte or word of the value
+    pub(crate) fn mask(self, val: i16) -> u32 {
+        match self {
+            Self::Byte => val as u8 as u32,
+            Self::Word => val as u16 as u32,
+        }
+    }
+
+    pub(crate) fn sign(self) -> u32 {
+        match self {
+            Self::Byte => 0x80,
+            Self::Word => 0x8000,
+        }
+    }
 }
 
+/// 8086 register as named in assembly.
+/// Byte registers are halves of ax, bx, cx and dx, e.g. writing `ah` changes `ax`,
+/// so reading a register always sees writes to the overlapping ones.
+/// The order of variants is stable, word registers sort before byte registers.
+/// Displayed lowercase, e.g. `ax`.
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
 pub enum Register {
     AX,
@@ -49,6 +73,10 @@ pub enum Register {
     BP,
     SI,
     DI,
+    ES,
+    CS,
+    SS,
+    DS,
     AL,
     BL,
     CL,
@@ -82,6 +110,27 @@ impl Register {
         }
     }
 
+    pub(crate) fn from_segment(sr: u8) -> Self {
+        match sr & 0b11 {
+            0b00 => Self::ES,
+            0b01 => Self::CS,
+            0b10 => Self::SS,
+            0b11 => Self::DS,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn accumulator(size: OperandSize) -> Self {
+        match size {
+            OperandSize::Byte => Self::AL,
+            OperandSize::Word => Self::AX,
+        }
+    }
+
+    pub(crate) fn is_segment(self) -> bool {
+        matches!(self, Self::ES | Self::CS | Self::SS | Self::DS)
+    }
+
     pub(crate) fn size(self) -> OperandSize {
         match self {
             Self::AL
@@ -99,7 +148,11 @@ impl Register {
             | Self::SP
             | Self::BP
             | Self::SI
-            | Self::DI => OperandSize::Word,
+            | Self::DI
+            | Self::ES
+            | Self::CS
+            | Self::SS
+            | Self::DS => OperandSize::Word,
         }
     }
 
@@ -114,18 +167,24 @@ impl Register {
             Self::SI => 6,
             Self::DI => 7,
 
-            Self::AH => 0,
-            Self::AL => 1,
-            Self::BH => 2,
-            Self::BL => 3,
-            Self::CH => 4,
-            Self::CL => 5,
-            Self::DH => 6,
-            Self::DL => 7,
+            Self::ES => 0,
+            Self::CS => 1,
+            Self::SS => 2,
+            Self::DS => 3,
+
+            Self::AL => 0,
+            Self::AH => 1,
+            Self::BL => 2,
+            Self::BH => 3,
+            Self::CL => 4,
+            Self::CH => 5,
+            Self::DL => 6,
+            Self::DH => 7,
         }
     }
 }
 
+/// Base and index registers of the r/m field, `DirectBP` is a direct address if mod is 00
 #[derive(Debug, Clone, Copy)]
 pub enum RegisterAddress {
     Empty,
@@ -155,15 +214,36 @@ impl RegisterAddress {
     }
 }
 
+/// Memory operand, displayed as in assembly, e.g. `es:[bx + si + 4]`
 #[derive(Debug, Clone, Copy)]
 pub struct EffectiveAddress {
     pub(crate) register: RegisterAddress,
     pub(crate) disp: i16,
+    // segment override prefix, the default segment is used if it's None
+    pub(crate) segment: Option<Register>,
 }
 
 impl EffectiveAddress {
     pub(crate) fn new(register: RegisterAddress, disp: i16) -> Self {
-        Self { register, disp }
+        Self {
+            register,
+            disp,
+            segment: None,
+        }
+    }
+
+    // BP based addresses are relative to the stack segment, the rest to the data segment
+    pub(crate) fn default_segment(&self) -> Register {
+        match self.register {
+            RegisterAddress::BPSI | RegisterAddress::BPDI | RegisterAddress::DirectBP => {
+                Register::SS
+            }
+            _ => Register::DS,
+        }
+    }
+
+    pub(crate) fn segment(&self) -> Register {
+        self.segment.unwrap_or_else(|| self.default_segment())
     }
 }
 
@@ -172,11 +252,22 @@ pub(crate) enum OperandEncoding {
     Accumulator(OperandSize),
     // TODO I don't like strings in ast, but it was fast to implement
     // Remove strings and move Display logic to decoder?
-    Jmp { offset: i8, label: String },
+    Jmp { offset: i16, label: String },
+    Far { segment: u16, offset: u16 },
     Immediate(i16),
     Register(Register),
 }
 
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum OperandKind {
+    Empty,
+    Accumulator,
+    Jmp,
+    Immediate,
+    Register,
+    Memory,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) enum Encoding {
     Empty,
@@ -187,12 +278,109 @@ pub(crate) enum Encoding {
     Memory(EffectiveAddress, OperandSize, OperandType),
 }
 
-#[derive(Debug, Clone)]
+impl Encoding {
+    pub(crate) fn kind(&self) -> OperandKind {
+        match self {
+            Self::Empty => OperandKind::Empty,
+            Self::Operand(OperandEncoding::Accumulator(_)) => OperandKind::Accumulator,
+            Self::Operand(OperandEncoding::Jmp { .. } | OperandEncoding::Far { .. }) => {
+                OperandKind::Jmp
+            }
+            Self::Operand(OperandEncoding::Immediate(_)) => OperandKind::Immediate,
+            Self::Operand(OperandEncoding::Register(_)) => OperandKind::Register,
+            Self::Memory(..) => OperandKind::Memory,
+        }
+    }
+
+    pub(crate) fn size(&self) -> OperandSize {
+        match self {
+            Self::Operand(OperandEncoding::Accumulator(size)) | Self::Memory(_, size, _) => *size,
+            Self::Operand(OperandEncoding::Register(reg)) => reg.size(),
+            _ => OperandSize::Word,
+        }
+    }
+}
+
+// repeat prefixes of string instructions, rep is also repe for cmps and scas
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Repeat {
+    Rep,
+    Repne,
+}
+
+/// Instruction mnemonic, displayed lowercase, e.g. `mov`.
+/// New variants are added as the decoder learns new instructions.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InstType {
     MOV,
+    XCHG,
+    LEA,
+    LDS,
+    LES,
+    LAHF,
+    SAHF,
+    XLAT,
+    IN,
+    OUT,
     ADD,
+    ADC,
     SUB,
+    SBB,
     CMP,
+    AND,
+    OR,
+    XOR,
+    TEST,
+    NOT,
+    INC,
+    DEC,
+    NEG,
+    DAA,
+    DAS,
+    AAA,
+    AAS,
+    AAM,
+    AAD,
+    CBW,
+    CWD,
+    MUL,
+    IMUL,
+    DIV,
+    IDIV,
+    SHL,
+    SHR,
+    SAR,
+    ROL,
+    ROR,
+    RCL,
+    RCR,
+    MOVS,
+    CMPS,
+    SCAS,
+    LODS,
+    STOS,
+    PUSH,
+    POP,
+    PUSHF,
+    POPF,
+    CALL,
+    JMP,
+    RET,
+    RETF,
+    INT,
+    INT3,
+    INTO,
+    IRET,
+    CLC,
+    STC,
+    CMC,
+    CLD,
+    STD,
+    CLI,
+    STI,
+    HLT,
+    WAIT,
+    NOP,
     JNZ,
     JE,
     JL,
@@ -216,11 +404,16 @@ pub enum InstType {
     Label(String),
 }
 
+/// Decoded instruction.
+/// Displayed in NASM syntax. Prefixes that don't apply to the instruction aren't displayed,
+/// e.g. `f3 c3` is `ret`, so the listing doesn't always assemble back to the same bytes.
 #[derive(Debug, Clone)]
 pub struct Inst {
     pub(crate) t: InstType,
     pub(crate) lhs: Encoding,
     pub(crate) rhs: Encoding,
+    pub(crate) repeat: Option<Repeat>,
+    pub(crate) lock: bool,
     pub(crate) length: usize,
 }
 
@@ -230,17 +423,68 @@ impl Inst {
             t: name,
             lhs,
             rhs,
+            repeat: None,
+            lock: false,
             length,
         }
     }
+
+    pub fn inst_type(&self) -> &InstType {
+        &self.t
+    }
+
+    /// Encoded length in bytes including prefixes
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    // string instructions and xlat are printed without operands, e.g. `rep movsb`
+    fn fmt_implicit(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.repeat, &self.t) {
+            (None, _) => Ok(()),
+            (Some(Repeat::Repne), _) => write!(f, "repne "),
+            (Some(Repeat::Rep), InstType::CMPS | InstType::SCAS) => write!(f, "repe "),
+            (Some(Repeat::Rep), _) => write!(f, "rep "),
+        }?;
+
+        // es:di destination can't be overridden, so it's the source
+        for encoding in [&self.lhs, &self.rhs] {
+            match encoding {
+                Encoding::Memory(
+                    EffectiveAddress {
+                        register: RegisterAddress::DI,
+                        ..
+                    },
+                    ..,
+                ) => {}
+                Encoding::Memory(
+                    EffectiveAddress {
+                        segment: Some(segment),
+                        ..
+                    },
+                    ..,
+                ) => write!(f, "{} ", segment)?,
+                _ => {}
+            }
+        }
+
+        let suffix = match self.lhs.size() {
+            OperandSize::Byte => "b",
+            OperandSize::Word => "w",
+        };
+        write!(f, "{}{}", self.t, suffix)
+    }
 }
 
 impl std::fmt::Display for EffectiveAddress {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(segment) = self.segment {
+            write!(f, "{}:", segment)?;
+        }
         write!(
             f,
             "[{}]",
-            if self.disp != 0 && matches!(self.register, RegisterAddress::Empty) {
+            if matches!(self.register, RegisterAddress::Empty) {
                 self.disp.to_string()
             } else {
                 let reg = match self.register {
@@ -290,6 +534,10 @@ impl std::fmt::Display for Register {
                 Self::BP => "bp",
                 Self::SI => "si",
                 Self::DI => "di",
+                Self::ES => "es",
+                Self::CS => "cs",
+                Self::SS => "ss",
+                Self::DS => "ds",
             }
         )
     }
@@ -302,6 +550,7 @@ impl std::fmt::Display for OperandEncoding {
             "{}",
             match self {
                 Self::Jmp { label: s, .. } => s.to_string(),
+                Self::Far { segment, offset } => format!("{}:{}", segment, offset),
                 Self::Accumulator(OperandSize::Byte) => "al".to_string(),
                 Self::Accumulator(OperandSize::Word) => "ax".to_string(),
                 Self::Immediate(e) => e.to_string(),
@@ -333,12 +582,22 @@ impl std::fmt::Display for Encoding {
                 Self::Operand(o) => o.to_string(),
                 Self::Memory(o, size, OperandType::Explicit) => format!("{} {}", size, o),
                 Self::Memory(o, _, OperandType::Implicit) => o.to_string(),
+                Self::Memory(o, _, OperandType::Far) => format!("far {}", o),
                 Self::Empty => "".to_string(),
             }
         )
     }
 }
 
+impl InstType {
+    pub(crate) fn is_string(&self) -> bool {
+        matches!(
+            self,
+            Self::MOVS | Self::CMPS | Self::SCAS | Self::LODS | Self::STOS
+        )
+    }
+}
+
 impl std::fmt::Display for InstType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -346,9 +605,74 @@ impl std::fmt::Display for InstType {
             "{}",
             match self {
                 Self::MOV => "mov",
+                Self::XCHG => "xchg",
+                Self::LEA => "lea",
+                Self::LDS => "lds",
+                Self::LES => "les",
+                Self::LAHF => "lahf",
+                Self::SAHF => "sahf",
+                Self::XLAT => "xlat",
+                Self::IN => "in",
+                Self::OUT => "out",
                 Self::ADD => "add",
+                Self::ADC => "adc",
                 Self::SUB => "sub",
+                Self::SBB => "sbb",
                 Self::CMP => "cmp",
+                Self::AND => "and",
+                Self::OR => "or",
+                Self::XOR => "xor",
+                Self::TEST => "test",
+                Self::NOT => "not",
+                Self::INC => "inc",
+                Self::DEC => "dec",
+                Self::NEG => "neg",
+                Self::DAA => "daa",
+                Self::DAS => "das",
+                Self::AAA => "aaa",
+                Self::AAS => "aas",
+                Self::AAM => "aam",
+                Self::AAD => "aad",
+                Self::CBW => "cbw",
+                Self::CWD => "cwd",
+                Self::MUL => "mul",
+                Self::IMUL => "imul",
+                Self::DIV => "div",
+                Self::IDIV => "idiv",
+                Self::SHL => "shl",
+                Self::SHR => "shr",
+                Self::SAR => "sar",
+                Self::ROL => "rol",
+                Self::ROR => "ror",
+                Self::RCL => "rcl",
+                Self::RCR => "rcr",
+                Self::MOVS => "movs",
+                Self::CMPS => "cmps",
+                Self::SCAS => "scas",
+                Self::LODS => "lods",
+                Self::STOS => "stos",
+                Self::PUSH => "push",
+                Self::POP => "pop",
+                Self::PUSHF => "pushf",
+                Self::POPF => "popf",
+                Self::CALL => "call",
+                Self::JMP => "jmp",
+                Self::RET => "ret",
+                Self::RETF => "retf",
+                Self::INT => "int",
+                Self::INT3 => "int3",
+                Self::INTO => "into",
+                Self::IRET => "iret",
+                Self::CLC => "clc",
+                Self::STC => "stc",
+                Self::CMC => "cmc",
+                Self::CLD => "cld",
+                Self::STD => "std",
+                Self::CLI => "cli",
+                Self::STI => "sti",
+                Self::HLT => "hlt",
+                Self::WAIT => "wait",
+                Self::NOP => "nop",
                 Self::JNZ => "jnz",
                 Self::JE => "je",
                 Self::JL => "jl",
@@ -377,7 +701,13 @@ impl std::fmt::Display for InstType {
 
 impl std::fmt::Display for Inst {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        if matches!(self.lhs, Encoding::Empty) {
+        if self.lock {
+            write!(f, "lock ")?;
+        }
+
+        if self.t.is_string() || matches!(self.t, InstType::XLAT) {
+            self.fmt_implicit(f)
+        } else if matches!(self.lhs, Encoding::Empty) {
             write!(f, "{}", self.t)
         } else if matches!(self.rhs, Encoding::Empty) {
             write!(f, "{} {}", self.t, self.lhs)
@@ -5,6 +5,11 @@ struct CmdOptions {
     flags: HashSet<String>,
     exec_path: String,
     dump_path: String,
+    rom: String,
+    load_at: String,
+    step_limit: String,
+    trace_format: String,
+    unclaimed_ports: String,
 }
 
 fn help() {
@@ -23,8 +28,23 @@ Commands:
     * Flags
         * `--quite` disables printing
         * `--print-ip` prints ip changes 
+        * `--print-segments` prints cs:ip changes as segment:offset pairs
+        * `--print-memory` prints every memory write as size[segment:offset]:old->new, e.g. `word[0000:1004]:0x0->0x1`, also the ones storing the same value; without it the trace has no memory
         * `--print-estimates` prints clock's cycles estimation for instructions
+        * `--undocumented-flags` sets flags undefined by the manual as the real 8086 does
+        * `--unclaimed-ports [policy]` handles access to a port without attached device: `float` (default) reads 0xFF, `ignore` reads 0, `error` fails; writes are dropped unless it fails
         * `--dump-memory [name]` creates a file with name [name] and dumps emulator's memory into it
+        * `--load-at [segment:offset]` loads the program at hex [segment:offset] instead of 0000:0000, e.g. `0100:0000` keeps the interrupt vector table free
+        * `--rom [address:name]` maps the file with name [name] as read-only memory at hex physical [address], e.g. `f0000:bios.bin`
+        * `--step-limit [count]` fails after [count] executed instructions
+        * `--trace-format [format]` prints the trace as `text` (default) or `jsonl`, a json object per instruction followed by the final state object, which is printed with the error on failure too
+
+Exit codes of failed emulation:
+* 2 - unsupported instruction
+* 3 - invalid opcode
+* 4 - access to unclaimed port
+* 5 - step limit reached
+* 6 - write refused by a memory mapped device
 "#
     );
     std::process::exit(1);
@@ -38,11 +58,36 @@ fn main() {
 
     let command = args.next().unwrap();
     let options = args.fold(CmdOptions::default(), |mut args, s| {
-        if args.flags.contains("dump") && args.dump_path.is_empty() {
+        if args.flags.contains("dump-memory") && args.dump_path.is_empty() {
             if s.starts_with("--") {
                 help();
             }
             args.dump_path = s.to_string();
+        } else if args.flags.contains("rom") && args.rom.is_empty() {
+            if s.starts_with("--") {
+                help();
+            }
+            args.rom = s.to_string();
+        } else if args.flags.contains("load-at") && args.load_at.is_empty() {
+            if s.starts_with("--") {
+                help();
+            }
+            args.load_at = s.to_string();
+        } else if args.flags.contains("step-limit") && args.step_limit.is_empty() {
+            if s.starts_with("--") {
+                help();
+            }
+            args.step_limit = s.to_string();
+        } else if args.flags.contains("trace-format") && args.trace_format.is_empty() {
+            if s.starts_with("--") {
+                help();
+            }
+            args.trace_format = s.to_string();
+        } else if args.flags.contains("unclaimed-ports") && args.unclaimed_ports.is_empty() {
+            if s.starts_with("--") {
+                help();
+            }
+            args.unclaimed_ports = s.to_string();
         } else if s.starts_with("--") {
             args.flags.insert(s.trim_start_matches("--").to_string());
         } else if args.exec_path.is_empty() {
@@ -59,30 +104,116 @@ fn main() {
 
     if command == "emulate" {
         let data = std::fs::read(&options.exec_path).expect("Can't open given file");
-        let decoded = sim8086::decoder::decode(data.into_iter());
-        let code: sim8086::emulator::Code = decoded
-            .into_iter()
-            .map(|x| x.expect("can't decode it"))
-            .collect();
-
-        let mut emulator = sim8086::emulator::Emulator::new(code);
+        let step_limit = if options.step_limit.is_empty() {
+            None
+        } else {
+            let Ok(limit) = options.step_limit.parse() else {
+                help();
+                return;
+            };
+            Some(limit)
+        };
+        let format = match options.trace_format.as_str() {
+            "" | "text" => sim8086::emulator::TraceFormat::Text,
+            "jsonl" => sim8086::emulator::TraceFormat::Jsonl,
+            _ => {
+                help();
+                return;
+            }
+        };
+        let unclaimed_ports = match options.unclaimed_ports.as_str() {
+            "" | "float" => sim8086::io::UnclaimedPort::Float,
+            "ignore" => sim8086::io::UnclaimedPort::Ignore,
+            "error" => sim8086::io::UnclaimedPort::Error,
+            _ => {
+                help();
+                return;
+            }
+        };
+        let mut emulator =
+            sim8086::emulator::Emulator::with_options(sim8086::emulator::EmulatorOptions {
+                undocumented_flags: options.flags.contains("undocumented-flags"),
+                unclaimed_ports,
+                step_limit,
+            });
+        if !options.load_at.is_empty() {
+            let Some((segment, offset)) = options.load_at.split_once(':') else {
+                help();
+                return;
+            };
+            let (Ok(segment), Ok(offset)) = (
+                u16::from_str_radix(segment, 16),
+                u16::from_str_radix(offset, 16),
+            ) else {
+                help();
+                return;
+            };
+            emulator.load_program_at(
+                sim8086::emulator::SegmentedAddress::new(segment, offset),
+                &data,
+            );
+        } else {
+            emulator.load_program(&data);
+        }
+        if !options.rom.is_empty() {
+            let Some((address, path)) = options.rom.split_once(':') else {
+                help();
+                return;
+            };
+            let Ok(address) = usize::from_str_radix(address, 16) else {
+                help();
+                return;
+            };
+            let rom = std::fs::read(path).expect("Can't open given rom file");
+            emulator.map_rom(address, rom);
+        }
         let mut tracer =
             sim8086::emulator::Tracer::with_options(sim8086::emulator::TracerOptions {
+                format,
                 with_ip: options.flags.contains("print-ip"),
+                with_segments: options.flags.contains("print-segments"),
+                with_memory: options.flags.contains("print-memory"),
                 with_estimate: options.flags.contains("print-estimates"),
                 with_trace: !options.flags.contains("quite"),
                 dump_path: options.dump_path,
             });
-        tracer.run(&mut emulator);
+        match tracer.run(&mut emulator) {
+            // listings without hlt end this way, so it isn't a failure
+            Ok(sim8086::emulator::Stop::EndOfProgram(address)) => {
+                eprintln!(
+                    "Emulation stopped: execution fell off the end of the program at {}",
+                    address
+                );
+            }
+            Ok(sim8086::emulator::Stop::Halted(_)) => {}
+            Err(e) => {
+                eprintln!("Emulation failed: {}\n{}", e, e.state);
+                std::process::exit(match e.kind {
+                    sim8086::emulator::EmulatorErrorKind::UnsupportedInstruction(_) => 2,
+                    sim8086::emulator::EmulatorErrorKind::InvalidOpcode(_) => 3,
+                    sim8086::emulator::EmulatorErrorKind::PortFault(_) => 4,
+                    sim8086::emulator::EmulatorErrorKind::StepLimit(_) => 5,
+                    sim8086::emulator::EmulatorErrorKind::MemoryFault(_) => 6,
+                });
+            }
+        }
     } else if command == "decode" {
         let data = std::fs::read(&options.exec_path).expect("Can't open given file");
         let decoded = sim8086::decoder::decode(data.into_iter());
+        let mut failed = false;
         for inst in decoded {
             match inst.map(|x| x.decode()) {
                 Ok(op) => println!("{}", op),
-                Err(e) => println!("{}", e),
+                // commented out so the listing still assembles
+                Err(e) => {
+                    failed = true;
+                    println!("; error {}", e);
+                }
             };
         }
+        if failed {
+            std::process::exit(1);
+        }
     } else {
         help();
     }
@@ -1,9 +1,58 @@
 use crate::ast::{
     EffectiveAddress, Encoding, Inst, InstType, Mode, OperandEncoding, OperandSize, OperandType,
-    Register, RegisterAddress,
+    Register, RegisterAddress, Repeat,
 };
 use std::collections::HashMap;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode { offset: usize, bytes: Vec<u8> },
+    // input ended in the middle of the instruction
+    Truncated { offset: usize, bytes: Vec<u8> },
+    // the opcode doesn't allow the mod or reg field of its mod reg r/m byte
+    InvalidModRm { offset: usize, bytes: Vec<u8> },
+}
+
+impl DecodeError {
+    pub fn offset(&self) -> usize {
+        match self {
+            Self::UnknownOpcode { offset, .. }
+            | Self::Truncated { offset, .. }
+            | Self::InvalidModRm { offset, .. } => *offset,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::UnknownOpcode { .. } => "unknown opcode",
+            Self::Truncated { .. } => "truncated instruction",
+            Self::InvalidModRm { .. } => "invalid mod reg r/m",
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Self::UnknownOpcode { bytes, .. }
+            | Self::Truncated { bytes, .. }
+            | Self::InvalidModRm { bytes, .. } => bytes,
+        }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let bytes = self
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{:#06x}: {} {}", self.offset(), self.reason(), bytes)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
 fn mode_to_write(rm: u8, mode: u8) -> usize {
     match Mode::from(mode) {
         Mode::Mem0Disp => {
@@ -64,7 +113,10 @@ struct JP {
     label: String,
 }
 impl JP {
-    const PREFIX: [(InstType, u8); 20] = [
+    const PREFIX: [(InstType, u8); 23] = [
+        (InstType::CALL, 0b11101000),
+        (InstType::JMP, 0b11101001),
+        (InstType::JMP, 0b11101011),
         (InstType::JNZ, 0b01110101),
         (InstType::JE, 0b01110100),
         (InstType::JL, 0b01111100),
@@ -102,7 +154,7 @@ impl JP {
     }
 
     fn new(op: u8) -> Self {
-        let mut v = Vec::with_capacity(2);
+        let mut v = Vec::with_capacity(3);
         v.push(op);
         Self {
             data: v,
@@ -110,8 +162,24 @@ impl JP {
         }
     }
 
-    fn get_offset(&self) -> i8 {
-        self.data.len() as i8 + self.data[1] as i8
+    // direct near call and jump have 16-bit displacement, the rest are short
+    fn disp_len(&self) -> usize {
+        match self.data[0] {
+            0b11101000 | 0b11101001 => 2,
+            _ => 1,
+        }
+    }
+
+    fn disp(&self) -> i16 {
+        if self.disp_len() == 2 {
+            (self.data[2] as i16) << 8 | self.data[1] as i16
+        } else {
+            self.data[1] as i8 as i16
+        }
+    }
+
+    fn get_offset(&self) -> i16 {
+        (self.data.len() as i16).wrapping_add(self.disp())
     }
 
     fn set_label(&mut self, label: String) {
@@ -120,22 +188,27 @@ impl JP {
 
     fn len(&self) -> usize {
         if self.data.len() == 1 {
-            return 1;
+            return self.disp_len();
         }
 
         0
     }
 
     fn push(&mut self, data: u8) {
-        assert!(self.data.len() < 2);
+        assert!(self.data.len() < 3);
         self.data.push(data);
     }
 
     fn decode(&self) -> Inst {
         let src = Encoding::Empty;
+        let label = if self.label.is_empty() {
+            format!("${:+}", self.get_offset())
+        } else {
+            self.label.to_string()
+        };
         let dst = Encoding::Operand(OperandEncoding::Jmp {
-            offset: self.data[1] as i8,
-            label: self.label.to_string(),
+            offset: self.disp(),
+            label,
         });
 
         let name = Self::inst_type(self.data[0]).unwrap();
@@ -146,14 +219,33 @@ impl JP {
 #[derive(Debug)]
 struct RM(Vec<u8>);
 impl RM {
-    const PREFIX: [(InstType, u8); 4] = [
+    const PREFIX: [(InstType, u8); 9] = [
         (InstType::ADD, 0b000000),
+        (InstType::ADC, 0b000100),
         (InstType::SUB, 0b001010),
+        (InstType::SBB, 0b000110),
         (InstType::MOV, 0b100010),
         (InstType::CMP, 0b001110),
+        (InstType::AND, 0b001000),
+        (InstType::OR, 0b000010),
+        (InstType::XOR, 0b001100),
     ];
 
     fn inst_type(op: u8) -> Option<InstType> {
+        // test has no d bit, r/m is always the destination
+        if (op >> 1) ^ 0b1000010 == 0 {
+            return Some(InstType::TEST);
+        }
+        if (op >> 1) ^ 0b1000011 == 0 {
+            return Some(InstType::XCHG);
+        }
+        match op {
+            0b10001101 => return Some(InstType::LEA),
+            0b11000101 => return Some(InstType::LDS),
+            0b11000100 => return Some(InstType::LES),
+            _ => {}
+        }
+
         let op_prefix = op >> 2;
         for (name, prefix) in Self::PREFIX {
             if (op_prefix ^ prefix) == 0 {
@@ -206,27 +298,98 @@ impl RM {
         self.0.push(data);
     }
 
+    // address loads need a memory operand
+    fn is_valid(&self) -> bool {
+        match Self::inst_type(self.0[0]) {
+            Some(InstType::LEA | InstType::LDS | InstType::LES) => self.mode() != 0b11,
+            _ => true,
+        }
+    }
+
     fn decode(&self) -> Inst {
-        let register = Register::from(self.reg(), self.w());
+        let name = Self::inst_type(self.0[0]).unwrap();
+        // address loads always target a word register
+        let (d, w) = match name {
+            InstType::LEA | InstType::LDS | InstType::LES => (0b1, 0b1),
+            _ => (self.d(), self.w()),
+        };
+        let register = Register::from(self.reg(), w);
         let mut src = Encoding::Operand(OperandEncoding::Register(register));
         let mut dst = mode_encode(
             &self.0,
             self.mode(),
             self.rm(),
-            self.w(),
+            w,
             register.size(),
             OperandType::Implicit,
         );
 
-        if self.d() == 0b1 {
+        if d == 0b1 {
             (src, dst) = (dst, src);
         };
 
-        let name = Self::inst_type(self.0[0]).unwrap();
         Inst::new(name, dst, src, self.0.len())
     }
 }
 
+#[derive(Debug)]
+struct SR(Vec<u8>);
+impl SR {
+    fn match_op(op: u8) -> bool {
+        ((op >> 2) ^ 0b100011) == 0 && op & 0b1 == 0
+    }
+    fn new(first: u8) -> Self {
+        let mut v = Vec::with_capacity(4);
+        v.push(first);
+        Self(v)
+    }
+    fn d(&self) -> u8 {
+        (self.0[0] >> 1) & 0b1
+    }
+    fn mode(&self) -> u8 {
+        (self.0[1] >> 6) & 0b11
+    }
+    fn sr(&self) -> u8 {
+        (self.0[1] >> 3) & 0b11
+    }
+    fn rm(&self) -> u8 {
+        self.0[1] & 0b111
+    }
+    fn len(&self) -> usize {
+        if self.0.len() == 1 {
+            return 1;
+        }
+
+        if 2 == self.0.len() {
+            mode_to_write(self.rm(), self.mode())
+        } else {
+            0
+        }
+    }
+    fn push(&mut self, data: u8) {
+        assert!(self.0.len() < 4);
+        self.0.push(data);
+    }
+    fn decode(&self) -> Inst {
+        let mut src =
+            Encoding::Operand(OperandEncoding::Register(Register::from_segment(self.sr())));
+        let mut dst = mode_encode(
+            &self.0,
+            self.mode(),
+            self.rm(),
+            0b1,
+            OperandSize::Word,
+            OperandType::Implicit,
+        );
+
+        if self.d() == 0b1 {
+            (src, dst) = (dst, src);
+        };
+
+        Inst::new(InstType::MOV, dst, src, self.0.len())
+    }
+}
+
 #[derive(Debug)]
 struct IM(Vec<u8>);
 enum IRMOpCode {
@@ -234,7 +397,12 @@ enum IRMOpCode {
     Mov,
     Cmp,
     Add,
+    Adc,
     Sub,
+    Sbb,
+    And,
+    Or,
+    Xor,
 }
 
 impl IRMOpCode {
@@ -256,17 +424,17 @@ impl IRMOpCode {
         let op = Self::get(op).unwrap();
         match op {
             Self::Mov => op,
-            Self::Empty => {
-                if reg == 0 {
-                    Self::Add
-                } else if reg ^ 0b101 == 0 {
-                    Self::Sub
-                } else if reg ^ 0b111 == 0 {
-                    Self::Cmp
-                } else {
-                    unreachable!()
-                }
-            }
+            Self::Empty => match reg {
+                0b000 => Self::Add,
+                0b001 => Self::Or,
+                0b010 => Self::Adc,
+                0b011 => Self::Sbb,
+                0b100 => Self::And,
+                0b101 => Self::Sub,
+                0b110 => Self::Xor,
+                0b111 => Self::Cmp,
+                _ => unreachable!(),
+            },
             _ => unreachable!(),
         }
     }
@@ -275,8 +443,13 @@ impl IRMOpCode {
         match self {
             Self::Mov => InstType::MOV,
             Self::Add => InstType::ADD,
+            Self::Adc => InstType::ADC,
             Self::Sub => InstType::SUB,
+            Self::Sbb => InstType::SBB,
             Self::Cmp => InstType::CMP,
+            Self::And => InstType::AND,
+            Self::Or => InstType::OR,
+            Self::Xor => InstType::XOR,
             Self::Empty => unreachable!(),
         }
     }
@@ -313,7 +486,7 @@ impl IM {
             self.w(),
         ) {
             (IRMOpCode::Mov, _, 1) => 2,
-            (IRMOpCode::Add | IRMOpCode::Sub | IRMOpCode::Cmp, 0, 1) => 2,
+            (_, 0, 1) => 2,
             (_, _, _) => 1,
         }
     }
@@ -331,6 +504,10 @@ impl IM {
         assert!(self.0.len() < 6);
         self.0.push(data);
     }
+    fn is_valid(&self) -> bool {
+        !matches!(IRMOpCode::get(self.0[0]), Some(IRMOpCode::Mov)) || self.reg() == 0
+    }
+
     fn decode(&self) -> Inst {
         let data_idx = 2 + mode_to_write(self.rm(), self.mode());
         let src = Encoding::Operand(OperandEncoding::Immediate(if self.data_len() == 2 {
@@ -339,10 +516,10 @@ impl IM {
             (self.0[data_idx] as i8) as i16
         }));
 
-        let mode = Mode::from(self.mode());
-        let size = match (mode, self.s(), self.w()) {
-            (_, 1, 1) => OperandSize::Word,
-            (_, _, _) => OperandSize::Byte,
+        let size = if self.w() == 1 {
+            OperandSize::Word
+        } else {
+            OperandSize::Byte
         };
 
         let dst = mode_encode(
@@ -407,8 +584,14 @@ impl IR {
 enum MAOpCode {
     Mov,
     Add,
+    Adc,
     Sub,
+    Sbb,
     Cmp,
+    And,
+    Or,
+    Xor,
+    Test,
 }
 impl MAOpCode {
     fn from(op: u8) -> Option<Self> {
@@ -416,10 +599,22 @@ impl MAOpCode {
             Some(Self::Mov)
         } else if ((op >> 1) ^ 0b0000010) == 0 {
             Some(Self::Add)
+        } else if ((op >> 1) ^ 0b0001010) == 0 {
+            Some(Self::Adc)
         } else if ((op >> 1) ^ 0b0010110) == 0 {
             Some(Self::Sub)
+        } else if ((op >> 1) ^ 0b0001110) == 0 {
+            Some(Self::Sbb)
         } else if ((op >> 1) ^ 0b0011110) == 0 {
             Some(Self::Cmp)
+        } else if ((op >> 1) ^ 0b0010010) == 0 {
+            Some(Self::And)
+        } else if ((op >> 1) ^ 0b0000110) == 0 {
+            Some(Self::Or)
+        } else if ((op >> 1) ^ 0b0011010) == 0 {
+            Some(Self::Xor)
+        } else if ((op >> 1) ^ 0b1010100) == 0 {
+            Some(Self::Test)
         } else {
             None
         }
@@ -428,8 +623,14 @@ impl MAOpCode {
         match self {
             Self::Mov => InstType::MOV,
             Self::Add => InstType::ADD,
+            Self::Adc => InstType::ADC,
             Self::Sub => InstType::SUB,
+            Self::Sbb => InstType::SBB,
             Self::Cmp => InstType::CMP,
+            Self::And => InstType::AND,
+            Self::Or => InstType::OR,
+            Self::Xor => InstType::XOR,
+            Self::Test => InstType::TEST,
         }
     }
 }
@@ -452,8 +653,12 @@ impl MA {
     fn d(&self) -> u8 {
         (self.0[0] >> 1) & 0b1
     }
+    // memory address is always a word, immediate data depends on w
+    fn is_wide_data(&self) -> bool {
+        self.w() == 1 || matches!(MAOpCode::from(self.0[0]), Some(MAOpCode::Mov))
+    }
     fn len(&self) -> usize {
-        if self.0.len() == 1 || self.w() == 1 && self.0.len() == 2 {
+        if self.0.len() == 1 || self.is_wide_data() && self.0.len() == 2 {
             1
         } else {
             0
@@ -464,22 +669,23 @@ impl MA {
         self.0.push(data);
     }
     fn decode(&self) -> Inst {
-        let is_wide = self.w() == 1;
-
-        let (dst, src_val) = if is_wide {
-            let mem = ((self.0[2] as i16) << 8) | self.0[1] as i16;
-            (OperandEncoding::Accumulator(OperandSize::Word), mem)
+        let size = if self.w() == 1 {
+            OperandSize::Word
         } else {
-            let mem = (self.0[1] as i8) as i16;
-            (OperandEncoding::Accumulator(OperandSize::Byte), mem)
+            OperandSize::Byte
         };
-        let mut dst = Encoding::Operand(dst);
+        let src_val = if self.is_wide_data() {
+            ((self.0[2] as i16) << 8) | self.0[1] as i16
+        } else {
+            (self.0[1] as i8) as i16
+        };
+        let mut dst = Encoding::Operand(OperandEncoding::Accumulator(size));
 
         let op_code = MAOpCode::from(self.0[0]).unwrap();
         let src = if matches!(op_code, MAOpCode::Mov) {
             let mut src = Encoding::Memory(
                 EffectiveAddress::new(RegisterAddress::Empty, src_val),
-                OperandSize::Word,
+                size,
                 OperandType::Implicit,
             );
             if self.d() == 1 {
@@ -496,13 +702,453 @@ impl MA {
     }
 }
 
+#[derive(Debug)]
+struct GR(Vec<u8>);
+impl GR {
+    // reg field of the mod reg r/m byte extends the opcode
+    const PREFIX: [(InstType, u8, u8); 24] = [
+        (InstType::POP, 0b10001111, 0b000),
+        (InstType::TEST, 0b11110110, 0b000),
+        (InstType::TEST, 0b11110111, 0b000),
+        (InstType::NOT, 0b11110110, 0b010),
+        (InstType::NOT, 0b11110111, 0b010),
+        (InstType::NEG, 0b11110110, 0b011),
+        (InstType::NEG, 0b11110111, 0b011),
+        (InstType::MUL, 0b11110110, 0b100),
+        (InstType::MUL, 0b11110111, 0b100),
+        (InstType::IMUL, 0b11110110, 0b101),
+        (InstType::IMUL, 0b11110111, 0b101),
+        (InstType::DIV, 0b11110110, 0b110),
+        (InstType::DIV, 0b11110111, 0b110),
+        (InstType::IDIV, 0b11110110, 0b111),
+        (InstType::IDIV, 0b11110111, 0b111),
+        (InstType::INC, 0b11111110, 0b000),
+        (InstType::INC, 0b11111111, 0b000),
+        (InstType::DEC, 0b11111110, 0b001),
+        (InstType::DEC, 0b11111111, 0b001),
+        (InstType::CALL, 0b11111111, 0b010),
+        (InstType::CALL, 0b11111111, 0b011),
+        (InstType::JMP, 0b11111111, 0b100),
+        (InstType::JMP, 0b11111111, 0b101),
+        (InstType::PUSH, 0b11111111, 0b110),
+    ];
+
+    fn inst_type(op: u8, reg: u8) -> Option<InstType> {
+        if Self::is_shift(op) {
+            return match reg {
+                0b000 => Some(InstType::ROL),
+                0b001 => Some(InstType::ROR),
+                0b010 => Some(InstType::RCL),
+                0b011 => Some(InstType::RCR),
+                0b100 => Some(InstType::SHL),
+                0b101 => Some(InstType::SHR),
+                0b111 => Some(InstType::SAR),
+                _ => None,
+            };
+        }
+
+        for (name, prefix, ext) in Self::PREFIX {
+            if (op ^ prefix) == 0 && (reg ^ ext) == 0 {
+                return Some(name);
+            }
+        }
+
+        None
+    }
+
+    // shifts and rotates, v bit selects count of 1 or cl
+    fn is_shift(op: u8) -> bool {
+        (op >> 2) ^ 0b110100 == 0
+    }
+
+    fn match_op(op: u8) -> bool {
+        Self::is_shift(op) || Self::PREFIX.iter().any(|(_, prefix, _)| (op ^ prefix) == 0)
+    }
+    fn new(first: u8) -> Self {
+        let mut v = Vec::with_capacity(4);
+        v.push(first);
+        Self(v)
+    }
+    fn w(&self) -> u8 {
+        self.0[0] & 0b1
+    }
+    fn mode(&self) -> u8 {
+        (self.0[1] >> 6) & 0b11
+    }
+    fn reg(&self) -> u8 {
+        (self.0[1] >> 3) & 0b111
+    }
+    fn rm(&self) -> u8 {
+        self.0[1] & 0b111
+    }
+    // immediate operand follows the displacement
+    fn data_len(&self) -> usize {
+        match Self::inst_type(self.0[0], self.reg()) {
+            Some(InstType::TEST) => 1 + self.w() as usize,
+            _ => 0,
+        }
+    }
+    fn len(&self) -> usize {
+        if self.0.len() == 1 {
+            return 1;
+        }
+
+        if 2 == self.0.len() {
+            mode_to_write(self.rm(), self.mode()) + self.data_len()
+        } else {
+            0
+        }
+    }
+    fn push(&mut self, data: u8) {
+        assert!(self.0.len() < 6);
+        self.0.push(data);
+    }
+    // far pointer can't be in a register
+    fn is_valid(&self) -> bool {
+        match Self::inst_type(self.0[0], self.reg()) {
+            Some(InstType::CALL | InstType::JMP) => self.reg() & 0b1 == 0 || self.mode() != 0b11,
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    fn decode(&self) -> Inst {
+        let size = if self.w() == 1 {
+            OperandSize::Word
+        } else {
+            OperandSize::Byte
+        };
+        let name = Self::inst_type(self.0[0], self.reg()).unwrap();
+        // indirect calls and jumps are near by default, odd reg field means far
+        let t = match (&name, self.reg() & 0b1) {
+            (InstType::CALL | InstType::JMP, 0b0) => OperandType::Implicit,
+            (InstType::CALL | InstType::JMP, 0b1) => OperandType::Far,
+            _ => OperandType::Explicit,
+        };
+        let dst = mode_encode(&self.0, self.mode(), self.rm(), self.w(), size, t);
+
+        let data_idx = 2 + mode_to_write(self.rm(), self.mode());
+        let src = match self.data_len() {
+            0 if Self::is_shift(self.0[0]) && (self.0[0] >> 1) & 0b1 == 1 => {
+                Encoding::Operand(OperandEncoding::Register(Register::CL))
+            }
+            0 if Self::is_shift(self.0[0]) => Encoding::Operand(OperandEncoding::Immediate(1)),
+            0 => Encoding::Empty,
+            1 => Encoding::Operand(OperandEncoding::Immediate(self.0[data_idx] as i8 as i16)),
+            _ => Encoding::Operand(OperandEncoding::Immediate(
+                ((self.0[data_idx + 1] as i16) << 8) | self.0[data_idx] as i16,
+            )),
+        };
+
+        Inst::new(name, dst, src, self.0.len())
+    }
+}
+
+// Instructions without mod reg r/m byte, the operand (if any) is encoded into the opcode
+#[derive(Debug)]
+struct OB(u8);
+impl OB {
+    fn decode_op(op: u8) -> Option<(InstType, Encoding)> {
+        let reg = |op| Encoding::Operand(OperandEncoding::Register(Register::from(op, 0b1)));
+        let segment =
+            |op| Encoding::Operand(OperandEncoding::Register(Register::from_segment(op >> 3)));
+        Some(match op {
+            0b01000000..=0b01000111 => (InstType::INC, reg(op)),
+            0b01001000..=0b01001111 => (InstType::DEC, reg(op)),
+            0b01010000..=0b01010111 => (InstType::PUSH, reg(op)),
+            0b01011000..=0b01011111 => (InstType::POP, reg(op)),
+            _ if op & 0b11100111 == 0b00000110 => (InstType::PUSH, segment(op)),
+            _ if op & 0b11100111 == 0b00000111 => (InstType::POP, segment(op)),
+            0b00100111 => (InstType::DAA, Encoding::Empty),
+            0b00101111 => (InstType::DAS, Encoding::Empty),
+            0b00110111 => (InstType::AAA, Encoding::Empty),
+            0b00111111 => (InstType::AAS, Encoding::Empty),
+            0b10011000 => (InstType::CBW, Encoding::Empty),
+            0b10011001 => (InstType::CWD, Encoding::Empty),
+            0b10010000 => (InstType::NOP, Encoding::Empty),
+            0b10010001..=0b10010111 => (InstType::XCHG, reg(op)),
+            0b10011011 => (InstType::WAIT, Encoding::Empty),
+            0b10011110 => (InstType::SAHF, Encoding::Empty),
+            0b10011111 => (InstType::LAHF, Encoding::Empty),
+            0b10011100 => (InstType::PUSHF, Encoding::Empty),
+            0b10011101 => (InstType::POPF, Encoding::Empty),
+            0b11000011 => (InstType::RET, Encoding::Empty),
+            0b11001011 => (InstType::RETF, Encoding::Empty),
+            0b11001100 => (InstType::INT3, Encoding::Empty),
+            0b11001110 => (InstType::INTO, Encoding::Empty),
+            0b11001111 => (InstType::IRET, Encoding::Empty),
+            // table lookup reads the byte at [bx + al]
+            0b11010111 => (
+                InstType::XLAT,
+                Encoding::Memory(
+                    EffectiveAddress::new(RegisterAddress::BX, 0),
+                    OperandSize::Byte,
+                    OperandType::Implicit,
+                ),
+            ),
+            0b11110100 => (InstType::HLT, Encoding::Empty),
+            0b11110101 => (InstType::CMC, Encoding::Empty),
+            0b11111000 => (InstType::CLC, Encoding::Empty),
+            0b11111001 => (InstType::STC, Encoding::Empty),
+            0b11111010 => (InstType::CLI, Encoding::Empty),
+            0b11111011 => (InstType::STI, Encoding::Empty),
+            0b11111100 => (InstType::CLD, Encoding::Empty),
+            0b11111101 => (InstType::STD, Encoding::Empty),
+            _ => return None,
+        })
+    }
+
+    fn match_op(op: u8) -> bool {
+        Self::decode_op(op).is_some()
+    }
+    fn new(first: u8) -> Self {
+        Self(first)
+    }
+    fn len(&self) -> usize {
+        0
+    }
+    fn push(&mut self, _data: u8) {
+        panic!("cant push")
+    }
+    fn decode(&self) -> Inst {
+        let (name, dst) = Self::decode_op(self.0).unwrap();
+        match name {
+            InstType::XCHG => Inst::new(
+                name,
+                Encoding::Operand(OperandEncoding::Accumulator(OperandSize::Word)),
+                dst,
+                1,
+            ),
+            InstType::XLAT => Inst::new(
+                name,
+                Encoding::Operand(OperandEncoding::Accumulator(OperandSize::Byte)),
+                dst,
+                1,
+            ),
+            _ => Inst::new(name, dst, Encoding::Empty, 1),
+        }
+    }
+}
+
+// String instructions, operands are implied: source at ds:si and destination at es:di
+#[derive(Debug)]
+struct ST(u8);
+impl ST {
+    fn inst_type(op: u8) -> Option<InstType> {
+        Some(match op >> 1 {
+            0b1010010 => InstType::MOVS,
+            0b1010011 => InstType::CMPS,
+            0b1010101 => InstType::STOS,
+            0b1010110 => InstType::LODS,
+            0b1010111 => InstType::SCAS,
+            _ => return None,
+        })
+    }
+
+    fn match_op(op: u8) -> bool {
+        Self::inst_type(op).is_some()
+    }
+    fn new(first: u8) -> Self {
+        Self(first)
+    }
+    fn len(&self) -> usize {
+        0
+    }
+    fn push(&mut self, _data: u8) {
+        panic!("cant push")
+    }
+    fn decode(&self) -> Inst {
+        let size = if self.0 & 0b1 == 1 {
+            OperandSize::Word
+        } else {
+            OperandSize::Byte
+        };
+        let source = Encoding::Memory(
+            EffectiveAddress::new(RegisterAddress::SI, 0),
+            size,
+            OperandType::Implicit,
+        );
+        let mut destination = EffectiveAddress::new(RegisterAddress::DI, 0);
+        destination.segment = Some(Register::ES);
+        let destination = Encoding::Memory(destination, size, OperandType::Implicit);
+        let accumulator = Encoding::Operand(OperandEncoding::Accumulator(size));
+
+        let name = Self::inst_type(self.0).unwrap();
+        let (dst, src) = match name {
+            InstType::MOVS => (destination, source),
+            InstType::CMPS => (source, destination),
+            InstType::SCAS => (accumulator, destination),
+            InstType::LODS => (accumulator, source),
+            _ => (destination, accumulator),
+        };
+        Inst::new(name, dst, src, 1)
+    }
+}
+
+// Port input and output, the port is either an immediate byte or dx
+#[derive(Debug)]
+struct IO(Vec<u8>);
+impl IO {
+    fn match_op(op: u8) -> bool {
+        op & 0b11110100 == 0b11100100
+    }
+    fn new(first: u8) -> Self {
+        let mut v = Vec::with_capacity(2);
+        v.push(first);
+        Self(v)
+    }
+    fn w(&self) -> u8 {
+        self.0[0] & 0b1
+    }
+    fn is_out(&self) -> bool {
+        (self.0[0] >> 1) & 0b1 == 1
+    }
+    fn is_variable(&self) -> bool {
+        (self.0[0] >> 3) & 0b1 == 1
+    }
+    fn len(&self) -> usize {
+        if self.0.len() == 1 && !self.is_variable() {
+            1
+        } else {
+            0
+        }
+    }
+    fn push(&mut self, data: u8) {
+        assert!(self.0.len() < 2);
+        self.0.push(data);
+    }
+    fn decode(&self) -> Inst {
+        let size = if self.w() == 1 {
+            OperandSize::Word
+        } else {
+            OperandSize::Byte
+        };
+        let port = if self.is_variable() {
+            Encoding::Operand(OperandEncoding::Register(Register::DX))
+        } else {
+            Encoding::Operand(OperandEncoding::Immediate(self.0[1] as i16))
+        };
+        let accumulator = Encoding::Operand(OperandEncoding::Accumulator(size));
+        if self.is_out() {
+            Inst::new(InstType::OUT, port, accumulator, self.0.len())
+        } else {
+            Inst::new(InstType::IN, accumulator, port, self.0.len())
+        }
+    }
+}
+
+// Instructions with immediate data right after the opcode
+#[derive(Debug)]
+struct ID(Vec<u8>);
+impl ID {
+    const PREFIX: [(InstType, u8, usize); 5] = [
+        (InstType::RET, 0b11000010, 2),
+        (InstType::RETF, 0b11001010, 2),
+        (InstType::INT, 0b11001101, 1),
+        (InstType::AAM, 0b11010100, 1),
+        (InstType::AAD, 0b11010101, 1),
+    ];
+
+    fn inst_type(op: u8) -> Option<(InstType, usize)> {
+        for (name, prefix, data_len) in Self::PREFIX {
+            if (op ^ prefix) == 0 {
+                return Some((name, data_len));
+            }
+        }
+
+        None
+    }
+
+    fn match_op(op: u8) -> bool {
+        Self::inst_type(op).is_some()
+    }
+    fn new(first: u8) -> Self {
+        let mut v = Vec::with_capacity(3);
+        v.push(first);
+        Self(v)
+    }
+    fn len(&self) -> usize {
+        if self.0.len() == 1 {
+            Self::inst_type(self.0[0]).unwrap().1
+        } else {
+            0
+        }
+    }
+    fn push(&mut self, data: u8) {
+        assert!(self.0.len() < 3);
+        self.0.push(data);
+    }
+    fn decode(&self) -> Inst {
+        let (name, data_len) = Self::inst_type(self.0[0]).unwrap();
+        let val = if data_len == 2 {
+            ((self.0[2] as i16) << 8) | self.0[1] as i16
+        } else {
+            self.0[1] as i16
+        };
+        let dst = match (&name, val) {
+            // decimal base is implied, as nasm prints it
+            (InstType::AAM | InstType::AAD, 10) => Encoding::Empty,
+            _ => Encoding::Operand(OperandEncoding::Immediate(val)),
+        };
+        Inst::new(name, dst, Encoding::Empty, self.0.len())
+    }
+}
+
+// Direct intersegment call and jump, the operand is segment:offset pointer
+#[derive(Debug)]
+struct FP(Vec<u8>);
+impl FP {
+    fn inst_type(op: u8) -> Option<InstType> {
+        match op {
+            0b10011010 => Some(InstType::CALL),
+            0b11101010 => Some(InstType::JMP),
+            _ => None,
+        }
+    }
+
+    fn match_op(op: u8) -> bool {
+        Self::inst_type(op).is_some()
+    }
+    fn new(first: u8) -> Self {
+        let mut v = Vec::with_capacity(5);
+        v.push(first);
+        Self(v)
+    }
+    fn len(&self) -> usize {
+        if self.0.len() == 1 {
+            4
+        } else {
+            0
+        }
+    }
+    fn push(&mut self, data: u8) {
+        assert!(self.0.len() < 5);
+        self.0.push(data);
+    }
+    fn decode(&self) -> Inst {
+        let offset = ((self.0[2] as u16) << 8) | self.0[1] as u16;
+        let segment = ((self.0[4] as u16) << 8) | self.0[3] as u16;
+        let dst = Encoding::Operand(OperandEncoding::Far { segment, offset });
+
+        let name = Self::inst_type(self.0[0]).unwrap();
+        Inst::new(name, dst, Encoding::Empty, self.0.len())
+    }
+}
+
 #[derive(Debug)]
 enum AsmOp {
     RM(RM),
+    SR(SR),
     IM(IM),
     IR(IR),
     MA(MA),
     JP(JP),
+    GR(GR),
+    OB(OB),
+    ID(ID),
+    FP(FP),
+    ST(ST),
+    IO(IO),
     Label(usize),
 }
 
@@ -510,10 +1156,17 @@ impl AsmOp {
     fn len(&self) -> usize {
         match self {
             Self::RM(r) => r.len(),
+            Self::SR(r) => r.len(),
             Self::IR(r) => r.len(),
             Self::MA(r) => r.len(),
             Self::IM(r) => r.len(),
             Self::JP(r) => r.len(),
+            Self::GR(r) => r.len(),
+            Self::OB(r) => r.len(),
+            Self::ID(r) => r.len(),
+            Self::FP(r) => r.len(),
+            Self::ST(r) => r.len(),
+            Self::IO(r) => r.len(),
             Self::Label(_) => 0,
         }
     }
@@ -521,21 +1174,44 @@ impl AsmOp {
     fn push(&mut self, data: u8) {
         match self {
             Self::RM(r) => r.push(data),
+            Self::SR(r) => r.push(data),
             Self::IR(r) => r.push(data),
             Self::MA(r) => r.push(data),
             Self::IM(r) => r.push(data),
             Self::JP(r) => r.push(data),
+            Self::GR(r) => r.push(data),
+            Self::OB(r) => r.push(data),
+            Self::ID(r) => r.push(data),
+            Self::FP(r) => r.push(data),
+            Self::ST(r) => r.push(data),
+            Self::IO(r) => r.push(data),
             Self::Label(_) => panic!("cant push"),
         }
     }
 
+    fn is_valid(&self) -> bool {
+        match self {
+            Self::RM(r) => r.is_valid(),
+            Self::IM(r) => r.is_valid(),
+            Self::GR(r) => r.is_valid(),
+            _ => true,
+        }
+    }
+
     fn decode(&self) -> Inst {
         match self {
             Self::RM(r) => r.decode(),
+            Self::SR(r) => r.decode(),
             Self::IR(r) => r.decode(),
             Self::MA(r) => r.decode(),
             Self::IM(r) => r.decode(),
             Self::JP(r) => r.decode(),
+            Self::GR(r) => r.decode(),
+            Self::OB(r) => r.decode(),
+            Self::ID(r) => r.decode(),
+            Self::FP(r) => r.decode(),
+            Self::ST(r) => r.decode(),
+            Self::IO(r) => r.decode(),
             Self::Label(s) => Inst::new(
                 InstType::Label(format!("label_{}:", s)),
                 Encoding::Empty,
@@ -546,9 +1222,45 @@ impl AsmOp {
     }
 }
 
+fn segment_prefix(op: u8) -> Option<Register> {
+    if op & 0b11100111 == 0b00100110 {
+        Some(Register::from_segment(op >> 3))
+    } else {
+        None
+    }
+}
+
+fn repeat_prefix(op: u8) -> Option<Repeat> {
+    match op {
+        0b11110011 => Some(Repeat::Rep),
+        0b11110010 => Some(Repeat::Repne),
+        _ => None,
+    }
+}
+
+fn lock_prefix(op: u8) -> bool {
+    op == 0b11110000
+}
+
+// es:di destination of string instructions can't be overridden
+fn with_segment(encoding: Encoding, segment: Option<Register>) -> Encoding {
+    match (encoding, segment) {
+        (Encoding::Memory(mut ea, size, t), Some(segment)) if ea.segment.is_none() => {
+            ea.segment = Some(segment);
+            Encoding::Memory(ea, size, t)
+        }
+        (encoding, _) => encoding,
+    }
+}
+
 #[derive(Debug)]
 pub struct Asm {
     pub ip: usize,
+    segment: Option<Register>,
+    repeat: Option<Repeat>,
+    lock: bool,
+    // prefix bytes read before the opcode, the same prefix can repeat
+    prefixes: usize,
     op: AsmOp,
 }
 
@@ -556,8 +1268,14 @@ impl Asm {
     fn new(ip: usize, op: u8) -> Option<Self> {
         Some(Asm {
             ip,
+            segment: None,
+            repeat: None,
+            lock: false,
+            prefixes: 0,
             op: if RM::match_op(op) {
                 AsmOp::RM(RM::new(op))
+            } else if SR::match_op(op) {
+                AsmOp::SR(SR::new(op))
             } else if IR::match_op(op) {
                 AsmOp::IR(IR::new(op))
             } else if IM::match_op(op) {
@@ -566,6 +1284,18 @@ impl Asm {
                 AsmOp::MA(MA::new(op))
             } else if JP::match_op(op) {
                 AsmOp::JP(JP::new(op))
+            } else if GR::match_op(op) {
+                AsmOp::GR(GR::new(op))
+            } else if OB::match_op(op) {
+                AsmOp::OB(OB::new(op))
+            } else if ID::match_op(op) {
+                AsmOp::ID(ID::new(op))
+            } else if FP::match_op(op) {
+                AsmOp::FP(FP::new(op))
+            } else if ST::match_op(op) {
+                AsmOp::ST(ST::new(op))
+            } else if IO::match_op(op) {
+                AsmOp::IO(IO::new(op))
             } else {
                 return None;
             },
@@ -581,59 +1311,274 @@ impl Asm {
     }
 
     pub fn decode(&self) -> Inst {
-        self.op.decode()
+        let mut inst = self.op.decode();
+        inst.lhs = with_segment(inst.lhs, self.segment);
+        inst.rhs = with_segment(inst.rhs, self.segment);
+        inst.repeat = self.repeat;
+        inst.lock = self.lock;
+        inst.length += self.prefixes;
+        inst
+    }
+}
+
+fn read_asm(it: &mut impl Iterator<Item = (usize, u8)>) -> Option<Result<Asm, DecodeError>> {
+    let (ip, mut first) = it.next()?;
+    let mut bytes = vec![first];
+    let mut segment = None;
+    let mut repeat = None;
+    let mut lock = false;
+    let mut prefixes = 0;
+    loop {
+        if let Some(prefix) = segment_prefix(first) {
+            segment = Some(prefix);
+        } else if let Some(prefix) = repeat_prefix(first) {
+            repeat = Some(prefix);
+        } else if lock_prefix(first) {
+            lock = true;
+        } else {
+            break;
+        }
+        prefixes += 1;
+        let Some((_, op)) = it.next() else {
+            return Some(Err(DecodeError::Truncated { offset: ip, bytes }));
+        };
+        bytes.push(op);
+        first = op;
+    }
+
+    let Some(mut asm) = Asm::new(ip, first) else {
+        return Some(Err(DecodeError::UnknownOpcode { offset: ip, bytes }));
+    };
+    asm.segment = segment;
+    asm.repeat = repeat;
+    asm.lock = lock;
+    asm.prefixes = prefixes;
+
+    loop {
+        let w = asm.len();
+        if w == 0 {
+            break;
+        }
+        for _ in 0..w {
+            let Some((_, data)) = it.next() else {
+                return Some(Err(DecodeError::Truncated { offset: ip, bytes }));
+            };
+            bytes.push(data);
+            asm.push(data);
+        }
     }
+
+    if !asm.op.is_valid() {
+        return Some(Err(DecodeError::InvalidModRm { offset: ip, bytes }));
+    }
+
+    Some(Ok(asm))
 }
 
-pub fn decode(it: impl Iterator<Item = u8>) -> Vec<Result<Asm, String>> {
-    let mut ops = vec![];
-    let mut it = it.enumerate();
+/// Decodes a single instruction from the given bytes,
+/// jumps are labeled relatively to the instruction's address, e.g. `jnz $-6`
+pub fn decode_inst(it: impl Iterator<Item = u8>) -> Option<Result<Inst, DecodeError>> {
+    read_asm(&mut it.enumerate()).map(|asm| asm.map(|asm| asm.decode()))
+}
+
+pub fn decode(it: impl Iterator<Item = u8>) -> Vec<Result<Asm, DecodeError>> {
+    let data = it.collect::<Vec<_>>();
+    let mut it = data.iter().copied().enumerate();
+    let mut ops = std::iter::from_fn(|| read_asm(&mut it)).collect::<Vec<_>>();
     let mut existed_labels = HashMap::new();
 
-    while let Some((ip, first)) = it.next() {
-        let Some(mut asm) = Asm::new(ip, first) else {
-            ops.push(Err(format!("{:b}", first)));
+    // only the start of an instruction or the end of the image can be labeled
+    let mut boundaries = ops
+        .iter()
+        .filter_map(|asm| asm.as_ref().ok().map(|asm| asm.ip))
+        .collect::<std::collections::HashSet<_>>();
+    boundaries.insert(data.len());
+
+    // ugly label handling code
+    let mut labels = vec![];
+    for asm in ops.iter_mut().flatten() {
+        let ip = asm.ip;
+        let prefix_len = asm.prefixes;
+        let AsmOp::JP(jump) = &mut asm.op else {
             continue;
         };
 
-        loop {
-            let w = asm.len();
-            if w == 0 {
-                break;
-            }
-            for _ in 0..w {
-                let Some((_, data)) = it.next() else {
-                    dbg!(asm);
-                    panic!()
-                };
-                asm.push(data);
-            }
+        let offset = jump.get_offset() as i64 + prefix_len as i64;
+        // ip wraps around inside of the code segment
+        let label_ip = (ip as i64 + offset).rem_euclid(0x10000) as usize;
+        if !boundaries.contains(&label_ip) {
+            jump.set_label(format!("${:+}", offset));
+            continue;
         }
 
-        // ugly label handling code
-        if let AsmOp::JP(jump) = &mut asm.op {
-            let offset = jump.get_offset();
-            let label_ip = (ip as i64 + offset as i64) as usize;
-
-            let label_number = existed_labels.len() + 1;
-            existed_labels.entry(label_ip).or_insert_with(|| {
-                ops.push(Ok(Asm {
-                    ip: label_ip - 1,
-                    op: AsmOp::Label(label_number),
-                }));
-                label_number
-            });
+        let label_number = existed_labels.len() + 1;
+        existed_labels.entry(label_ip).or_insert_with(|| {
+            labels.push(Ok(Asm {
+                ip: label_ip,
+                segment: None,
+                repeat: None,
+                lock: false,
+                prefixes: 0,
+                op: AsmOp::Label(label_number),
+            }));
+            label_number
+        });
 
-            let label_name = format!("label_{}", existed_labels[&label_ip]);
-            jump.set_label(label_name.clone());
-        };
-        ops.push(Ok(asm));
+        let label_name = format!("label_{}", existed_labels[&label_ip]);
+        jump.set_label(label_name.clone());
     }
+    ops.extend(labels);
 
-    ops.sort_by(|a, b| match (a, b) {
-        (Ok(a), Ok(b)) => a.ip.cmp(&b.ip),
-        _ => std::cmp::Ordering::Equal,
+    // stable sort, a label goes right before the entry at its offset
+    ops.sort_by_key(|op| match op {
+        Ok(Asm {
+            ip,
+            op: AsmOp::Label(_),
+            ..
+        }) => (*ip, 0),
+        Ok(asm) => (asm.ip, 1),
+        Err(e) => (e.offset(), 1),
     });
 
     ops
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(bytes: &[u8]) -> Vec<String> {
+        decode(bytes.iter().copied())
+            .into_iter()
+            .map(|asm| match asm {
+                Ok(asm) => asm.decode().to_string(),
+                Err(e) => format!("; error {}", e),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn label_goes_before_jump_target() {
+        // nop; dec cx; jnz -3
+        assert_eq!(
+            listing(&[0x90, 0x49, 0x75, 0xfd]),
+            ["nop", "label_1:", "dec cx", "jnz label_1"]
+        );
+    }
+
+    #[test]
+    fn label_at_end_of_image() {
+        assert_eq!(listing(&[0xeb, 0x00]), ["jmp label_1", "label_1:"]);
+    }
+
+    #[test]
+    fn jump_before_image_is_relative() {
+        // call -16 from offset 1 targets 0xfff4
+        assert_eq!(listing(&[0x90, 0xe8, 0xf0, 0xff]), ["nop", "call $-13"]);
+    }
+
+    #[test]
+    fn jump_into_instruction_is_relative() {
+        // jnz targets the immediate of mov ax, 1
+        assert_eq!(
+            listing(&[0xb8, 0x01, 0x00, 0x75, 0xfc]),
+            ["mov ax, 1", "jnz $-2"]
+        );
+    }
+
+    #[test]
+    fn logic_instructions() {
+        assert_eq!(
+            listing(&[0x21, 0xd8, 0xa8, 0x80, 0xf7, 0xd0, 0x0c, 0x0f, 0x31, 0xc0]),
+            [
+                "and ax, bx",
+                "test al, -128",
+                "not ax",
+                "or al, 15",
+                "xor ax, ax"
+            ]
+        );
+    }
+
+    #[test]
+    fn shift_instructions() {
+        assert_eq!(
+            listing(&[0xd3, 0xe0, 0xd3, 0xf8, 0xd2, 0xc0, 0xd3, 0xd8, 0xd0, 0xe0]),
+            [
+                "shl ax, cl",
+                "sar ax, cl",
+                "rol al, cl",
+                "rcr ax, cl",
+                "shl al, 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn divide_instructions() {
+        assert_eq!(
+            listing(&[0xf7, 0xfb, 0xf6, 0xfb, 0xf7, 0xf3]),
+            ["idiv bx", "idiv bl", "div bx"]
+        );
+    }
+
+    #[test]
+    fn carry_instructions() {
+        assert_eq!(
+            listing(&[0x11, 0xda, 0x19, 0xca, 0x83, 0xd2, 0x00]),
+            ["adc dx, bx", "sbb dx, cx", "adc dx, 0"]
+        );
+    }
+
+    #[test]
+    fn bcd_instructions() {
+        assert_eq!(
+            listing(&[0x27, 0x2f, 0x37, 0x3f, 0xd4, 0x0a, 0xd5, 0x0a]),
+            ["daa", "das", "aaa", "aas", "aam", "aad"]
+        );
+    }
+
+    #[test]
+    fn repeated_string_instructions() {
+        assert_eq!(
+            listing(&[0xf3, 0xa4, 0xf3, 0xa6, 0xf2, 0xae, 0xf3, 0xaa, 0xf2, 0xa7]),
+            [
+                "rep movsb",
+                "repe cmpsb",
+                "repne scasb",
+                "rep stosb",
+                "repne cmpsw"
+            ]
+        );
+    }
+
+    #[test]
+    fn repeat_with_other_prefixes() {
+        // rep rep stosb; rep es: movsb; es: rep movsb
+        let bytes = [0xf3, 0xf3, 0xaa, 0xf3, 0x26, 0xa4, 0x26, 0xf3, 0xa4];
+        let lengths: Vec<usize> = decode(bytes.into_iter())
+            .iter()
+            .map(|asm| asm.as_ref().unwrap().decode().length())
+            .collect();
+        assert_eq!(lengths, [3, 3, 3]);
+        assert_eq!(
+            listing(&bytes),
+            ["rep stosb", "rep es movsb", "rep es movsb"]
+        );
+    }
+
+    #[test]
+    fn repeated_segment_prefix() {
+        // es: es: mov ax, [bx]; hlt
+        let ops = decode([0x26, 0x26, 0x8b, 0x07, 0xf4].into_iter());
+        let lengths: Vec<usize> = ops
+            .iter()
+            .map(|asm| asm.as_ref().unwrap().decode().length())
+            .collect();
+        assert_eq!(lengths, [4, 1]);
+        assert_eq!(
+            listing(&[0x26, 0x26, 0x8b, 0x07, 0xf4]),
+            ["mov ax, es:[bx]", "hlt"]
+        );
+    }
+}
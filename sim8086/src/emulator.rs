@@ -1,404 +1,1599 @@
 use crate::ast::{
-    EffectiveAddress, Encoding, Inst, InstType, OperandEncoding, OperandSize, Register,
-    RegisterAddress,
+    EffectiveAddress, Encoding, Inst, InstType, OperandEncoding, OperandKind, OperandSize,
+    OperandType, Register, RegisterAddress, Repeat,
 };
-use std::collections::{HashMap, HashSet};
+use crate::decoder::DecodeError;
+use crate::io::{PortBus, PortDevice, UnclaimedPort, UnclaimedPortError};
+use crate::memory::{MemoryBus, MemoryDevice, MemoryFault, MEMORY_SIZE};
+use crate::observer::Observer;
+use std::cell::RefCell;
+use std::collections::HashSet;
 
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
-struct Registers(u128);
+struct Registers {
+    general: u128,
+    segment: u64,
+}
 impl Registers {
     fn load(self, reg: Register) -> i16 {
         let reg_size = reg.size().size();
         let reg_idx = reg.to_idx() as u8;
+        if reg.is_segment() {
+            return ((self.segment >> (reg_size * reg_idx)) & 0xFFFF) as i16;
+        }
+
         let reg_mask = (1 << reg_size) - 1;
-        ((self.0 >> (reg_size * reg_idx)) & reg_mask) as i16
+        ((self.general >> (reg_size * reg_idx)) & reg_mask) as i16
     }
     fn store(self, reg: Register, val: i16) -> Registers {
-        let val = (val as u16) as u128;
         let reg_size = reg.size().size();
         let reg_idx = reg.to_idx() as u8;
+        if reg.is_segment() {
+            let shift = reg_size * reg_idx;
+            let segment = self.segment & !(0xFFFF << shift) | ((val as u16 as u64) << shift);
+            return Self { segment, ..self };
+        }
 
-        let left = self.0 & (((1 << ((7 - reg_idx) * reg_size)) - 1) << ((reg_idx + 1) * reg_size));
-        let mid = val << (reg_idx * reg_size);
-        let right = self.0 & ((1 << (reg_idx * reg_size)) - 1);
-        Self(left | mid | right)
+        let reg_mask = (1 << reg_size) - 1;
+        let shift = reg_size * reg_idx;
+        let val = (val as u16) as u128 & reg_mask;
+        Self {
+            general: self.general & !(reg_mask << shift) | (val << shift),
+            ..self
+        }
     }
 }
 
+/// Flags register, displayed as letters of the set flags, e.g. `CZ`
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
-struct Flags(u16);
+pub struct Flags(u16);
 
 macro_rules! bit_field_is {
     ($name:ident, $shift:literal) => {
-        fn $name(self) -> bool {
+        pub fn $name(self) -> bool {
             let shift: u8 = $shift;
             ((self.0 >> shift) & 1) == 1
         }
     };
 }
 
-macro_rules! bit_field_unset {
-    ($name:ident, $shift:literal) => {
-        fn $name(self) -> Self {
-            let shift: u8 = $shift;
-            Self(self.0 & !(1u16 << shift))
-        }
-    };
-}
-
-macro_rules! bit_field_set {
+macro_rules! bit_field_with {
     ($name:ident, $shift:literal) => {
-        fn $name(self) -> Self {
+        pub fn $name(self, val: bool) -> Self {
             let shift: u8 = $shift;
-            Self(self.0 | (1u16 << shift))
+            Self(self.0 & !(1u16 << shift) | ((val as u16) << shift))
         }
     };
 }
 
 impl Flags {
+    // reserved bits 1 and 12-15 are always set on 8086
+    const RESERVED: u16 = 0xF002;
+
+    fn to_word(self) -> i16 {
+        (self.0 | Self::RESERVED) as i16
+    }
+
+    fn from_word(val: i16) -> Self {
+        Self(val as u16 & !Self::RESERVED)
+    }
+
+    /// Flags word as `pushf` stores it
+    pub fn bits(self) -> u16 {
+        self.to_word() as u16
+    }
+
     // https://en.wikipedia.org/wiki/FLAGS_register
     bit_field_is!(is_cf, 0);
-    bit_field_set!(set_cf, 0);
-    bit_field_unset!(unset_cf, 0);
+    bit_field_with!(with_cf, 0);
 
     bit_field_is!(is_pf, 2);
-    bit_field_set!(set_pf, 2);
-    bit_field_unset!(unset_pf, 2);
+    bit_field_with!(with_pf, 2);
 
     bit_field_is!(is_af, 4);
-    bit_field_set!(set_af, 4);
-    bit_field_unset!(unset_af, 4);
+    bit_field_with!(with_af, 4);
 
     bit_field_is!(is_zf, 6);
-    bit_field_set!(set_zf, 6);
-    bit_field_unset!(unset_zf, 6);
+    bit_field_with!(with_zf, 6);
 
     bit_field_is!(is_sf, 7);
-    bit_field_set!(set_sf, 7);
-    bit_field_unset!(unset_sf, 7);
+    bit_field_with!(with_sf, 7);
+
+    bit_field_is!(is_tf, 8);
+    bit_field_with!(with_tf, 8);
+
+    bit_field_is!(is_if, 9);
+    bit_field_with!(with_if, 9);
+
+    bit_field_is!(is_df, 10);
+    bit_field_with!(with_df, 10);
+
+    bit_field_is!(is_of, 11);
+    bit_field_with!(with_of, 11);
 }
 
 impl std::fmt::Display for Flags {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}{}{}{}{}",
+            "{}{}{}{}{}{}{}{}{}",
             if self.is_cf() { "C" } else { "" },
             if self.is_pf() { "P" } else { "" },
             if self.is_af() { "A" } else { "" },
             if self.is_zf() { "Z" } else { "" },
             if self.is_sf() { "S" } else { "" },
+            if self.is_tf() { "T" } else { "" },
+            if self.is_if() { "I" } else { "" },
+            if self.is_df() { "D" } else { "" },
+            if self.is_of() { "O" } else { "" },
         )
     }
 }
 
-#[derive(Debug, Default)]
-pub struct Code {
-    insts: Vec<Inst>,
-    ip_insts_idx: HashMap<usize, usize>,
+fn lsb_of(bit: bool) -> u32 {
+    bit as u32
 }
 
-impl Code {
-    fn get_inst(&self, ip: usize) -> Option<Inst> {
-        self.ip_insts_idx
-            .get(&ip)
-            .map(|idx| self.insts[*idx].clone())
+fn sign_of(bit: bool, sign: u32) -> u32 {
+    if bit {
+        sign
+    } else {
+        0
     }
 }
 
-impl From<Vec<crate::decoder::Asm>> for Code {
-    fn from(value: Vec<crate::decoder::Asm>) -> Self {
-        Self {
-            ip_insts_idx: value
-                .iter()
-                .enumerate()
-                .map(|iasm| (iasm.1.ip, iasm.0))
-                .collect(),
-            insts: value.into_iter().map(|x| x.decode()).collect(),
-        }
+// sign or zero extends the operand of multiplication or division
+fn extend(t: &InstType, size: OperandSize, val: i16) -> i64 {
+    match (t, size) {
+        (InstType::IMUL | InstType::IDIV, OperandSize::Byte) => val as i8 as i64,
+        (InstType::IMUL | InstType::IDIV, OperandSize::Word) => val as i64,
+        _ => size.mask(val) as i64,
     }
 }
 
-impl FromIterator<crate::decoder::Asm> for Code {
-    fn from_iter<T: IntoIterator<Item = crate::decoder::Asm>>(iter: T) -> Self {
-        Self::from(iter.into_iter().collect::<Vec<crate::decoder::Asm>>())
+// minimal register clocks and the spread of the data dependent range
+fn estimate_multiply(t: &InstType, size: OperandSize) -> (u16, u16) {
+    match (t, size) {
+        (InstType::MUL, OperandSize::Byte) => (70, 7),
+        (InstType::MUL, OperandSize::Word) => (118, 15),
+        (_, OperandSize::Byte) => (80, 18),
+        (_, OperandSize::Word) => (128, 26),
     }
 }
 
-fn estimate_ea(ea: EffectiveAddress) -> u8 {
-    match (ea.register, ea.disp) {
-        (RegisterAddress::Empty, 0) => 0,
-        (RegisterAddress::Empty, _) => 6,
-        (
-            RegisterAddress::BX
-            | RegisterAddress::DirectBP
-            | RegisterAddress::SI
-            | RegisterAddress::DI,
-            0,
-        ) => 5,
-        (
-            RegisterAddress::BX
-            | RegisterAddress::DirectBP
-            | RegisterAddress::SI
-            | RegisterAddress::DI,
-            _,
-        ) => 9,
-        (RegisterAddress::BPDI | RegisterAddress::BXSI, 0) => 7,
-        (RegisterAddress::BPSI | RegisterAddress::BXDI, 0) => 8,
-        (RegisterAddress::BPDI | RegisterAddress::BXSI, _) => 11,
-        (RegisterAddress::BPSI | RegisterAddress::BXDI, _) => 12,
+// clocks of a single and of a repeated iteration
+fn estimate_string(t: &InstType) -> (u16, u16) {
+    match t {
+        InstType::MOVS => (18, 17),
+        InstType::CMPS => (22, 22),
+        InstType::SCAS => (15, 15),
+        InstType::LODS => (12, 13),
+        _ => (11, 10),
     }
 }
 
-#[derive(Debug, Default)]
+fn estimate_divide(t: &InstType, size: OperandSize) -> (u16, u16) {
+    match (t, size) {
+        (InstType::DIV, OperandSize::Byte) => (80, 10),
+        (InstType::DIV, OperandSize::Word) => (144, 18),
+        (_, OperandSize::Byte) => (101, 11),
+        (_, OperandSize::Word) => (165, 19),
+    }
+}
+
+// the microcode loops once per bit, so the clocks grow with the number of set bits
+fn estimate_range(spread: u16, size: OperandSize, val: i16) -> u16 {
+    spread * size.mask(val).count_ones() as u16 / size.size() as u16
+}
+
+/// Logical address, displayed as `segment:offset` in hex
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct SegmentedAddress {
+    segment: u16,
+    offset: u16,
+}
+
+impl SegmentedAddress {
+    pub fn new(segment: u16, offset: u16) -> Self {
+        Self { segment, offset }
+    }
+
+    pub fn segment(self) -> u16 {
+        self.segment
+    }
+
+    pub fn offset(self) -> u16 {
+        self.offset
+    }
+
+    // offset wraps around inside of the 64 KiB segment
+    fn add(self, val: u16) -> Self {
+        Self::new(self.segment, self.offset.wrapping_add(val))
+    }
+
+    /// 20-bit physical address, it wraps around at the top of 1 MiB
+    pub fn physical(self) -> usize {
+        (((self.segment as usize) << 4) + self.offset as usize) % MEMORY_SIZE
+    }
+}
+
+impl std::fmt::Display for SegmentedAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.segment, self.offset)
+    }
+}
+
+fn estimate_ea(ea: EffectiveAddress) -> u8 {
+    let segment_override = if ea.segment.is_some() { 2 } else { 0 };
+    segment_override
+        + match (ea.register, ea.disp) {
+            (RegisterAddress::Empty, 0) => 0,
+            (RegisterAddress::Empty, _) => 6,
+            (
+                RegisterAddress::BX
+                | RegisterAddress::DirectBP
+                | RegisterAddress::SI
+                | RegisterAddress::DI,
+                0,
+            ) => 5,
+            (
+                RegisterAddress::BX
+                | RegisterAddress::DirectBP
+                | RegisterAddress::SI
+                | RegisterAddress::DI,
+                _,
+            ) => 9,
+            (RegisterAddress::BPDI | RegisterAddress::BXSI, 0) => 7,
+            (RegisterAddress::BPSI | RegisterAddress::BXDI, 0) => 8,
+            (RegisterAddress::BPDI | RegisterAddress::BXSI, _) => 11,
+            (RegisterAddress::BPSI | RegisterAddress::BXDI, _) => 12,
+        }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
 struct Clock {
-    value: u8,
+    value: u16,
     transfer: u8,
     ea: u8,
 }
 
+#[derive(Debug, Clone, Copy)]
+enum StackOp {
+    Push(i16),
+    Pop(i16),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PortOp {
+    In(u16, OperandSize, i16),
+    Out(u16, OperandSize, i16),
+}
+
+impl std::fmt::Display for PortOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (direction, port, size, val) = match *self {
+            PortOp::In(port, size, val) => ("in", port, size, val),
+            PortOp::Out(port, size, val) => ("out", port, size, val),
+        };
+        write!(
+            f,
+            "{}:{}:{:#x}={:#x}",
+            direction,
+            size,
+            port,
+            size.mask(val)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    Register(Register, i16, i16),
+    MemoryRead(SegmentedAddress, OperandSize, i16),
+    MemoryWrite(SegmentedAddress, OperandSize, i16, i16),
+    Interrupt(u8, SegmentedAddress),
+}
+
+/// Executed instruction and its effects
 #[derive(Debug)]
-struct Step {
+pub struct Step {
     inst: Inst,
-    ip: (u16, u16),
-    register: Option<(Register, i16, i16)>,
+    bytes: Vec<u8>,
+    ip: (SegmentedAddress, SegmentedAddress),
+    register: Vec<(Register, i16, i16)>,
+    memory: Vec<(SegmentedAddress, OperandSize, i16, i16)>,
+    stack: Option<((i16, i16), Vec<StackOp>)>,
+    port: Option<PortOp>,
     flags: Option<(Flags, Flags)>,
     clock: Clock,
 }
 
+impl Step {
+    pub fn inst(&self) -> &Inst {
+        &self.inst
+    }
+
+    /// Encoded instruction as it was fetched, including prefixes
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Address of the instruction
+    pub fn address(&self) -> SegmentedAddress {
+        self.ip.0
+    }
+
+    /// Address of the next instruction to execute
+    pub fn next_address(&self) -> SegmentedAddress {
+        self.ip.1
+    }
+
+    /// Estimated clocks including effective address calculation and odd address transfers
+    pub fn clocks(&self) -> u16 {
+        self.clock.value + self.clock.ea as u16 + self.clock.transfer as u16
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct EmulatorOptions {
+    // flags the manual leaves undefined are set the way the silicon does it
+    pub undocumented_flags: bool,
+    pub unclaimed_ports: UnclaimedPort,
+    // emulation fails after the given number of steps, e.g. to stop an endless loop
+    pub step_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum EmulatorErrorKind {
+    // decoded, but the emulator doesn't know the instruction or its operands
+    UnsupportedInstruction(Box<Inst>),
+    InvalidOpcode(DecodeError),
+    // access to a port without attached device, see `UnclaimedPort::Error`
+    PortFault(UnclaimedPortError),
+    // write refused by a memory mapped device, see `MemoryDevice`
+    MemoryFault(MemoryFault),
+    StepLimit(u64),
+}
+
+/// Registers and flags at the failed instruction, ip points to the instruction itself
+#[derive(Debug, Clone, Copy)]
+pub struct CpuState {
+    cs_ip: SegmentedAddress,
+    registers: Registers,
+    flags: Flags,
+}
+
+impl CpuState {
+    pub fn cs_ip(&self) -> SegmentedAddress {
+        self.cs_ip
+    }
+
+    pub fn register(&self, reg: Register) -> u16 {
+        self.registers.load(reg) as u16
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+}
+
+impl std::fmt::Display for CpuState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Register::*;
+        for reg in [AX, BX, CX, DX, SP, BP, SI, DI, ES, CS, SS, DS] {
+            let val = self.registers.load(reg) as u16;
+            writeln!(f, "{:>8}: {:#06x} ({})", reg.to_string(), val, val)?;
+        }
+        writeln!(f, "   cs:ip: {}", self.cs_ip)?;
+        write!(f, "   flags: {}", self.flags)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EmulatorError {
+    pub kind: EmulatorErrorKind,
+    pub state: CpuState,
+}
+
+impl std::fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = self.state.cs_ip;
+        match &self.kind {
+            EmulatorErrorKind::UnsupportedInstruction(inst) => {
+                write!(f, "unsupported instruction `{}` at {}", inst, at)
+            }
+            // decoder offset is relative to cs:ip
+            EmulatorErrorKind::InvalidOpcode(e) => {
+                let bytes = e.bytes().iter().map(|byte| format!("{:02x}", byte));
+                let bytes = bytes.collect::<Vec<_>>().join(" ");
+                write!(f, "{} {} at {}", e.reason(), bytes, at)
+            }
+            EmulatorErrorKind::PortFault(e) => {
+                write!(f, "access to unclaimed port {:#06x} at {}", e.port, at)
+            }
+            EmulatorErrorKind::MemoryFault(e) => {
+                write!(f, "device refused write to {:05x} at {}", e.address, at)
+            }
+            EmulatorErrorKind::StepLimit(limit) => {
+                write!(f, "step limit of {} reached at {}", limit, at)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+/// Why the emulation stopped without an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// `hlt` at the address was executed
+    Halted(SegmentedAddress),
+    /// Execution fell through the last instruction of a loaded program image,
+    /// e.g. the program has no `hlt`. A jump, call or return to the end of an image
+    /// doesn't stop, code loaded there is executed.
+    EndOfProgram(SegmentedAddress),
+}
+
+/// 8086 with 1 MiB of memory, all registers and flags start zeroed.
+/// It executes until `hlt`, until execution falls off the end of a loaded program image
+/// or until an error occurs, see `Stop` and `EmulatorError`.
+/// Code outside of the loaded images, e.g. an interrupt handler, is executed as well.
 #[derive(Debug, Default)]
 pub struct Emulator {
+    opt: EmulatorOptions,
     ip: u16,
     flags: Flags,
     registers: Registers,
-    code: Code,
-    memory: Vec<u8>,
-    register_update: Option<(Register, i16, i16)>,
-    // stack: Vec<u8>,
+    memory: MemoryBus,
+    ports: PortBus,
+    port_update: Option<PortOp>,
+    // physical addresses of the loaded program images,
+    // emulation stops once ip falls off the end of one of them
+    programs: Vec<std::ops::Range<usize>>,
+    // the last instruction transferred control, so ip didn't fall through to the next one
+    jumped: bool,
+    register_update: Vec<(Register, i16, i16)>,
+    // address of the hlt instruction that stopped the cpu
+    halted: Option<SegmentedAddress>,
+    // repeated string instruction is restarted until cx runs out, as on the real cpu
+    repeating: bool,
+    steps: u64,
+    // registers, flags, the repeat and jump states before the executing instruction
+    checkpoint: (Registers, Flags, bool, bool),
+    // first write refused by a device, the instruction fails once it's executed
+    memory_fault: Option<MemoryFault>,
+    // events of the executing instruction, memory reads are recorded through a shared reference
+    events: RefCell<Vec<Event>>,
+    stack_update: Option<((i16, i16), Vec<StackOp>)>,
 }
 
 impl Emulator {
-    pub fn new(code: Code) -> Self {
+    pub fn new() -> Self {
+        Self::with_options(EmulatorOptions::default())
+    }
+
+    pub fn with_options(opt: EmulatorOptions) -> Self {
         Self {
-            code,
-            memory: vec![0; 1024 * 1024],
+            ports: PortBus::new(opt.unclaimed_ports),
+            opt,
             ..Self::default()
         }
     }
 
-    fn run(&mut self) {
-        while self.step().is_some() {}
+    /// Maps read-only memory at the physical address, writes to it are ignored
+    pub fn map_rom(&mut self, start: usize, data: Vec<u8>) {
+        self.memory.map_rom(start, data);
+    }
+
+    /// Maps the device to the range of physical addresses, e.g. video buffer at 0xB8000
+    pub fn map_device(
+        &mut self,
+        range: std::ops::Range<usize>,
+        device: impl MemoryDevice + 'static,
+    ) {
+        self.memory.map_device(range, Box::new(device));
+    }
+
+    /// Attaches the device to the inclusive range of ports
+    pub fn attach_ports(
+        &mut self,
+        ports: std::ops::RangeInclusive<u16>,
+        device: impl PortDevice + 'static,
+    ) {
+        self.ports.attach(ports, Box::new(device));
+    }
+
+    /// Loads the program image to cs:ip, which is 0000:0000 initially.
+    /// The emulation stops once execution falls off the end of the image.
+    pub fn load_program(&mut self, program: &[u8]) {
+        let start = self.cs_ip();
+        self.load_data_at(start, program);
+        self.programs
+            .push(start.physical()..start.physical() + program.len());
+    }
+
+    /// Moves cs:ip to the address and loads the program image there
+    pub fn load_program_at(&mut self, address: SegmentedAddress, program: &[u8]) {
+        self.set_cs_ip(address);
+        self.load_program(program);
+    }
+
+    /// Copies the bytes to memory at the address, e.g. data or an interrupt handler.
+    /// Neither cs:ip nor the loaded program images change.
+    /// Bytes a mapped device refuses are dropped and rom isn't written.
+    pub fn load_data_at(&mut self, address: SegmentedAddress, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let _ = self.memory.write(address.add(i as u16).physical(), *byte);
+        }
+    }
+
+    pub fn register(&self, reg: Register) -> u16 {
+        self.load_register(reg) as u16
+    }
+
+    /// Unlike the executed instructions, it isn't traced
+    pub fn set_register(&mut self, reg: Register, val: u16) {
+        self.registers = self.registers.store(reg, val as i16);
+    }
+
+    pub fn cs_ip(&self) -> SegmentedAddress {
+        SegmentedAddress::new(self.load_register(Register::CS) as u16, self.ip)
+    }
+
+    pub fn set_cs_ip(&mut self, address: SegmentedAddress) {
+        self.set_register(Register::CS, address.segment);
+        self.ip = address.offset;
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.flags = flags;
+    }
+
+    pub fn memory(&self) -> &MemoryBus {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut MemoryBus {
+        &mut self.memory
+    }
+
+    /// Address of the `hlt` instruction that stopped the cpu
+    pub fn halted(&self) -> Option<SegmentedAddress> {
+        self.halted
+    }
+
+    /// Reason of the stop, `None` while the emulator can execute further
+    pub fn stopped(&self) -> Option<Stop> {
+        if let Some(address) = self.halted {
+            return Some(Stop::Halted(address));
+        }
+        let cs_ip = self.cs_ip();
+        let physical = cs_ip.physical();
+        let in_program = self.programs.iter().any(|image| image.contains(&physical));
+        let at_end = self.programs.iter().any(|image| image.end == physical);
+        if at_end && !in_program && !self.jumped {
+            return Some(Stop::EndOfProgram(cs_ip));
+        }
+        None
+    }
+
+    fn fetch(&self) -> Option<Result<Inst, DecodeError>> {
+        if self.stopped().is_some() {
+            return None;
+        }
+
+        // an instruction can't continue past the end of its image
+        let cs_ip = self.cs_ip();
+        let image = self
+            .programs
+            .iter()
+            .rev()
+            .find(|image| image.contains(&cs_ip.physical()));
+        let memory = &self.memory;
+        crate::decoder::decode_inst(
+            (0..)
+                .map(|i| cs_ip.add(i).physical())
+                .take_while(|address| image.is_none_or(|image| image.contains(address)))
+                .map(|address| memory.read(address)),
+        )
     }
 
-    fn step(&mut self) -> Option<Step> {
-        let inst = self.code.get_inst(self.ip as usize)?;
-        let from_ip = self.ip;
+    /// Executes instructions until the program stops
+    pub fn run(&mut self) -> Result<Stop, EmulatorError> {
+        self.run_with(&mut ())
+    }
+
+    pub fn run_with(&mut self, observer: &mut dyn Observer) -> Result<Stop, EmulatorError> {
+        loop {
+            if let Some(stop) = self.stopped() {
+                return Ok(stop);
+            }
+            self.execute(observer)?;
+        }
+    }
+
+    /// Executes a single instruction and returns it, `None` if the program has stopped.
+    /// A repeated string instruction executes one iteration per step.
+    /// On error cs:ip still points at the failed instruction.
+    pub fn step(&mut self) -> Result<Option<Inst>, EmulatorError> {
+        self.step_with(&mut ())
+    }
+
+    pub fn step_with(
+        &mut self,
+        observer: &mut dyn Observer,
+    ) -> Result<Option<Inst>, EmulatorError> {
+        Ok(self.execute(observer)?.map(|step| step.inst))
+    }
+
+    // restores registers, flags and ip of the failed instruction, so the state points at it,
+    // memory and port writes it has already done stay
+    fn fault(&mut self, at: SegmentedAddress, kind: EmulatorErrorKind) -> EmulatorError {
+        (self.registers, self.flags, self.repeating, self.jumped) = self.checkpoint;
+        self.ip = at.offset;
+        self.memory_fault = None;
+        self.register_update.clear();
+        self.stack_update = None;
+        self.port_update = None;
+        self.events.borrow_mut().clear();
+        EmulatorError {
+            kind,
+            state: CpuState {
+                cs_ip: at,
+                registers: self.registers,
+                flags: self.flags,
+            },
+        }
+    }
+
+    fn execute(&mut self, observer: &mut dyn Observer) -> Result<Option<Step>, EmulatorError> {
+        let from_ip = self.cs_ip();
+        self.checkpoint = (self.registers, self.flags, self.repeating, self.jumped);
+        let inst = match self.fetch() {
+            None => return Ok(None),
+            Some(Ok(inst)) => inst,
+            Some(Err(e)) => return Err(self.fault(from_ip, EmulatorErrorKind::InvalidOpcode(e))),
+        };
+        if let Some(limit) = self.opt.step_limit.filter(|limit| self.steps >= *limit) {
+            return Err(self.fault(from_ip, EmulatorErrorKind::StepLimit(limit)));
+        }
+        let bytes: Vec<u8> = (0..inst.length as u16)
+            .map(|i| self.memory.read(from_ip.add(i).physical()))
+            .collect();
+        observer.before_instruction(from_ip, &inst);
+        // ip points to the next instruction during execution, as on the real cpu
+        self.ip = self.ip.wrapping_add(inst.length as u16);
+        self.jumped = false;
         let from_flags = self.flags;
-        let mut clock = 0;
+        let clock;
         let mut clock_ea = 0;
         let mut clock_transfer = 0;
 
         match (&inst.t, &inst.lhs, &inst.rhs) {
+            (InstType::MOV, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                self.store(lhs, self.load(rhs));
+            }
+            (InstType::XCHG, lhs, rhs) => {
+                use OperandKind::*;
+                (clock, clock_ea, clock_transfer) = match (lhs.kind(), rhs.kind()) {
+                    (Accumulator, _) => (3, 0, 0),
+                    (Memory, _) => self.estimate_one_operand(lhs, 0, 17, 2),
+                    (_, Memory) => self.estimate_one_operand(rhs, 0, 17, 2),
+                    _ => (4, 0, 0),
+                };
+                let (lhs_val, rhs_val) = (self.load(lhs), self.load(rhs));
+                self.store(lhs, rhs_val);
+                self.store(rhs, lhs_val);
+            }
+            (InstType::LEA, lhs, &Encoding::Memory(ea, ..)) => {
+                clock = 2;
+                clock_ea = estimate_ea(ea);
+                let address = self.translate_effective_address(ea);
+                self.store(lhs, address.offset as i16);
+            }
+            (t @ (InstType::LDS | InstType::LES), lhs, &Encoding::Memory(ea, ..)) => {
+                clock = 16;
+                clock_ea = estimate_ea(ea);
+                let address = self.translate_effective_address(ea);
+                clock_transfer = self.estimate_transfer(address, OperandSize::Word) * 2;
+                let (segment, offset) = self.load_far_pointer(address);
+                self.store(lhs, offset as i16);
+                let segment_register = match t {
+                    InstType::LDS => Register::DS,
+                    _ => Register::ES,
+                };
+                self.store_register(segment_register, segment as i16);
+            }
+            // reserved bit 1 is set in ah as it is in the pushed flags
+            (InstType::LAHF, Encoding::Empty, Encoding::Empty) => {
+                clock = 4;
+                self.store_register(Register::AH, self.flags.to_word() & 0xFF);
+            }
+            (InstType::SAHF, Encoding::Empty, Encoding::Empty) => {
+                clock = 4;
+                // only sf, zf, af, pf and cf are loaded
+                let ah = self.load_register(Register::AH) as u16 & 0b11010101;
+                self.flags = Flags(self.flags.0 & 0xFF00 | ah);
+            }
+            (InstType::XLAT, lhs, &Encoding::Memory(ea, size, _)) => {
+                clock = 11;
+                let al = self.load(lhs) as u8;
+                let address = self.translate_effective_address(ea).add(al as u16);
+                self.store(lhs, self.load_memory(address, size));
+            }
+            (InstType::IN, lhs, port) => {
+                (clock, clock_transfer) = self.estimate_port(lhs.size(), port);
+                let port = self.load(port) as u16;
+                let val = self
+                    .load_port(port, lhs.size())
+                    .map_err(|e| self.fault(from_ip, EmulatorErrorKind::PortFault(e)))?;
+                self.store(lhs, val);
+            }
+            (InstType::OUT, port, rhs) => {
+                (clock, clock_transfer) = self.estimate_port(rhs.size(), port);
+                let port = self.load(port) as u16;
+                self.store_port(port, self.load(rhs), rhs.size())
+                    .map_err(|e| self.fault(from_ip, EmulatorErrorKind::PortFault(e)))?;
+            }
+            (InstType::ADD, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let val = self.add(lhs.size(), self.load(lhs), self.load(rhs));
+                self.store(lhs, val);
+            }
+            (InstType::ADC, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let carry = self.flags.is_cf();
+                let val = self.add_carry(lhs.size(), self.load(lhs), self.load(rhs), carry);
+                self.store(lhs, val);
+            }
+            (InstType::SUB, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let val = self.sub(lhs.size(), self.load(lhs), self.load(rhs));
+                self.store(lhs, val);
+            }
+            (InstType::SBB, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let borrow = self.flags.is_cf();
+                let val = self.sub_borrow(lhs.size(), self.load(lhs), self.load(rhs), borrow);
+                self.store(lhs, val);
+            }
+            (InstType::CMP, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                self.sub(lhs.size(), self.load(lhs), self.load(rhs));
+            }
+            (InstType::AND, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let val = self.logic(lhs.size(), self.load(lhs) & self.load(rhs));
+                self.store(lhs, val);
+            }
+            (InstType::OR, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let val = self.logic(lhs.size(), self.load(lhs) | self.load(rhs));
+                self.store(lhs, val);
+            }
+            (InstType::XOR, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                let val = self.logic(lhs.size(), self.load(lhs) ^ self.load(rhs));
+                self.store(lhs, val);
+            }
+            (InstType::TEST, lhs, rhs) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_two_operands(&inst);
+                self.logic(lhs.size(), self.load(lhs) & self.load(rhs));
+            }
             (
-                InstType::MOV,
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Immediate(val)),
+                t @ (InstType::SHL
+                | InstType::SHR
+                | InstType::SAR
+                | InstType::ROL
+                | InstType::ROR
+                | InstType::RCL
+                | InstType::RCR),
+                lhs,
+                rhs,
             ) => {
-                self.store_register(reg1, val);
+                let count = self.load(rhs) as u8;
+                (clock, clock_ea, clock_transfer) = match rhs {
+                    Encoding::Operand(OperandEncoding::Register(_)) => {
+                        let (clock, ea, transfer) = self.estimate_one_operand(lhs, 8, 20, 2);
+                        (clock + 4 * count as u16, ea, transfer)
+                    }
+                    _ => self.estimate_one_operand(lhs, 2, 15, 2),
+                };
+                let val = self.shift(t, lhs.size(), self.load(lhs), count);
+                self.store(lhs, val);
+            }
+            (t @ (InstType::DAA | InstType::DAS), Encoding::Empty, Encoding::Empty) => {
                 clock = 4;
+                self.decimal_adjust(t);
+            }
+            (t @ (InstType::AAA | InstType::AAS), Encoding::Empty, Encoding::Empty) => {
+                clock = 4;
+                self.ascii_adjust(t);
+            }
+            (InstType::AAM, base, Encoding::Empty) => {
+                clock = 83;
+                let base = match base {
+                    Encoding::Empty => 10,
+                    base => self.load(base) as u8,
+                };
+                let al = self.load_register(Register::AL) as u8;
+                // zero base faults the same way division does
+                match al.checked_div(base) {
+                    Some(quotient) => {
+                        self.store_register(Register::AH, quotient as i16);
+                        let al = self.update_flags(OperandSize::Byte, (al % base) as u32);
+                        self.store_register(Register::AL, al);
+                        if self.opt.undocumented_flags {
+                            self.flags = self.flags.with_cf(false).with_of(false).with_af(false);
+                        }
+                    }
+                    None => {
+                        clock_transfer = self.estimate_stack_transfer() * 3;
+                        self.interrupt(0);
+                    }
+                }
+            }
+            (InstType::AAD, base, Encoding::Empty) => {
+                clock = 60;
+                let base = match base {
+                    Encoding::Empty => 10,
+                    base => self.load(base) as u8,
+                };
+                let al = self.load_register(Register::AL);
+                let product = (self.load_register(Register::AH) as u8).wrapping_mul(base);
+                // silicon adds the product to al with a regular byte addition
+                if self.opt.undocumented_flags {
+                    self.add(OperandSize::Byte, al, product as i16);
+                }
+                let al = self.update_flags(OperandSize::Byte, al as u32 + product as u32);
+                self.store_register(Register::AX, al);
+            }
+            (InstType::CBW, Encoding::Empty, Encoding::Empty) => {
+                clock = 2;
+                let al = self.load_register(Register::AL) as i8;
+                self.store_register(Register::AX, al as i16);
+            }
+            (InstType::CWD, Encoding::Empty, Encoding::Empty) => {
+                clock = 5;
+                let ax = self.load_register(Register::AX);
+                self.store_register(Register::DX, if ax < 0 { -1 } else { 0 });
+            }
+            (t @ (InstType::MUL | InstType::IMUL), src, Encoding::Empty) => {
+                let (min, spread) = estimate_multiply(t, src.size());
+                let (value, ea, transfer) = self.estimate_one_operand(src, min, min + 6, 1);
+                let val = self.load(src);
+                clock = value + estimate_range(spread, src.size(), val);
+                (clock_ea, clock_transfer) = (ea, transfer);
+                self.multiply(t, src.size(), val);
+            }
+            (t @ (InstType::DIV | InstType::IDIV), src, Encoding::Empty) => {
+                let (min, spread) = estimate_divide(t, src.size());
+                let (value, ea, transfer) = self.estimate_one_operand(src, min, min + 6, 1);
+                (clock_ea, clock_transfer) = (ea, transfer);
+                clock = match self.divide(t, src.size(), self.load(src)) {
+                    Some(quotient) => value + estimate_range(spread, src.size(), quotient),
+                    None => {
+                        clock_transfer += self.estimate_stack_transfer() * 3;
+                        self.interrupt(0);
+                        value
+                    }
+                };
+            }
+            (t @ (InstType::INC | InstType::DEC), lhs, Encoding::Empty) => {
+                // single byte form 0x40-0x4f takes one clock less than FF /0 and FF /1,
+                // a memory operand doesn't use the register clock, so its last byte doesn't matter
+                let reg_clock = match bytes.last() {
+                    Some(0x40..=0x4F) => 2,
+                    _ => 3,
+                };
+                (clock, clock_ea, clock_transfer) =
+                    self.estimate_one_operand(lhs, reg_clock, 15, 2);
+                // carry isn't affected
+                let carry = self.flags.is_cf();
+                let val = match t {
+                    InstType::INC => self.add(lhs.size(), self.load(lhs), 1),
+                    _ => self.sub(lhs.size(), self.load(lhs), 1),
+                };
+                self.flags = self.flags.with_cf(carry);
+                self.store(lhs, val);
+            }
+            (InstType::NEG, lhs, Encoding::Empty) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_one_operand(lhs, 3, 16, 2);
+                let val = self.sub(lhs.size(), 0, self.load(lhs));
+                self.store(lhs, val);
+            }
+            (t, lhs, rhs) if t.is_string() => {
+                let (single, repeated) = estimate_string(t);
+                let first = !std::mem::take(&mut self.repeating);
+                // every memory operand is transferred once per iteration
+                clock_transfer = [lhs, rhs]
+                    .into_iter()
+                    .map(|encoding| self.estimate_memory(encoding, 1).1)
+                    .sum();
+                match inst.repeat {
+                    None => {
+                        clock = single;
+                        self.string(t, lhs, rhs);
+                    }
+                    Some(_) if self.load_register(Register::CX) == 0 => {
+                        clock = 9;
+                        clock_transfer = 0;
+                    }
+                    Some(repeat) => {
+                        clock = repeated + if first { 9 } else { 0 };
+                        self.string(t, lhs, rhs);
+                        let done = self.decrement_cx() == 0
+                            || match (t, repeat) {
+                                (InstType::CMPS | InstType::SCAS, Repeat::Rep) => {
+                                    !self.flags.is_zf()
+                                }
+                                (InstType::CMPS | InstType::SCAS, Repeat::Repne) => {
+                                    self.flags.is_zf()
+                                }
+                                _ => false,
+                            };
+                        if !done {
+                            self.ip = from_ip.offset;
+                            self.repeating = true;
+                        }
+                    }
+                }
+            }
+            (InstType::NOT, lhs, Encoding::Empty) => {
+                (clock, clock_ea, clock_transfer) = self.estimate_one_operand(lhs, 3, 16, 2);
+                self.store(lhs, !self.load(lhs));
             }
             (
-                InstType::MOV,
-                &Encoding::Memory(ea, size, _),
-                &Encoding::Operand(OperandEncoding::Immediate(val)),
-            ) => self.store_memory(self.translate_effective_address(ea), val, size),
-            (
-                InstType::MOV,
-                &Encoding::Memory(ea, size, _),
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
+                t @ (InstType::JNZ
+                | InstType::JE
+                | InstType::JL
+                | InstType::JLE
+                | InstType::JB
+                | InstType::JBE
+                | InstType::JP
+                | InstType::JO
+                | InstType::JS
+                | InstType::JNL
+                | InstType::JG
+                | InstType::JNB
+                | InstType::JA
+                | InstType::JNP
+                | InstType::JNO
+                | InstType::JNS
+                | InstType::LOOP
+                | InstType::LOOPZ
+                | InstType::LOOPNZ
+                | InstType::JCXZ),
+                &Encoding::Operand(OperandEncoding::Jmp { offset, .. }),
+                Encoding::Empty,
             ) => {
-                self.store_memory(
-                    self.translate_effective_address(ea),
-                    self.load_register(reg1),
-                    size,
-                );
-                clock = 9;
-                clock_ea = estimate_ea(ea);
+                let (taken, clock_taken, clock_not_taken) = self.branch(t);
+                if taken {
+                    self.jump(self.ip.wrapping_add(offset as u16));
+                    clock = clock_taken;
+                } else {
+                    clock = clock_not_taken;
+                }
             }
             (
-                InstType::MOV,
+                InstType::PUSH,
                 &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Register(reg2)),
+                Encoding::Empty,
             ) => {
-                self.store_register(reg1, self.load_register(reg2));
-                clock = 2;
+                let val = if let Register::SP = reg1 {
+                    // 8086 pushes the already decremented value of sp
+                    self.load_register(reg1).wrapping_sub(2)
+                } else {
+                    self.load_register(reg1)
+                };
+                clock_transfer = self.estimate_stack_transfer();
+                self.push(val);
+                clock = if reg1.is_segment() { 10 } else { 11 };
+            }
+            (InstType::PUSH, &Encoding::Memory(ea, size, _), Encoding::Empty) => {
+                let address = self.translate_effective_address(ea);
+                clock_transfer = self.estimate_stack_transfer();
+                if address.physical() % 2 == 1 {
+                    clock_transfer += 4;
+                }
+                self.push(self.load_memory(address, size));
+                clock = 16;
+                clock_ea = estimate_ea(ea);
+            }
+            (InstType::PUSHF, Encoding::Empty, Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer();
+                self.push(self.flags.to_word());
+                clock = 10;
             }
             (
-                InstType::MOV,
+                InstType::POP,
                 &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Memory(ea, size, _),
+                Encoding::Empty,
             ) => {
-                self.store_register(
-                    reg1,
-                    self.load_memory(self.translate_effective_address(ea), size),
-                );
+                clock_transfer = self.estimate_stack_transfer();
+                let val = self.pop();
+                self.store_register(reg1, val);
                 clock = 8;
+            }
+            (InstType::POP, &Encoding::Memory(ea, size, _), Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer();
+                let val = self.pop();
+                let address = self.translate_effective_address(ea);
+                if address.physical() % 2 == 1 {
+                    clock_transfer += 4;
+                }
+                self.store_memory(address, val, size);
+                clock = 17;
                 clock_ea = estimate_ea(ea);
             }
+            (InstType::POPF, Encoding::Empty, Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer();
+                let val = self.pop();
+                self.flags = Flags::from_word(val);
+                clock = 8;
+            }
             (
-                InstType::ADD,
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Immediate(val)),
+                InstType::JMP,
+                &Encoding::Operand(OperandEncoding::Jmp { offset, .. }),
+                Encoding::Empty,
             ) => {
-                self.store_add_register(reg1, val);
-                clock = 4;
+                self.jump(self.ip.wrapping_add(offset as u16));
+                clock = 15;
             }
             (
-                InstType::ADD,
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Register(reg2)),
+                InstType::JMP,
+                &Encoding::Operand(OperandEncoding::Far { segment, offset }),
+                Encoding::Empty,
             ) => {
-                self.store_add_register(reg1, self.load_register(reg2));
-                clock = 3;
+                self.jump_far(segment, offset);
+                clock = 15;
             }
             (
-                InstType::ADD,
+                InstType::JMP,
                 &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Memory(ea, size, _),
+                Encoding::Empty,
             ) => {
+                self.jump(self.load_register(reg1) as u16);
+                clock = 11;
+            }
+            (InstType::JMP, &Encoding::Memory(ea, _, OperandType::Far), Encoding::Empty) => {
                 let address = self.translate_effective_address(ea);
-                self.store_add_register(reg1, self.load_memory(address, size));
-                clock = 9;
+                let (segment, offset) = self.load_far_pointer(address);
+                self.jump_far(segment, offset);
+                clock = 24;
                 clock_ea = estimate_ea(ea);
-                if address % 2 == 1 {
-                    clock_transfer = 4;
+                if address.physical() % 2 == 1 {
+                    clock_transfer = 4 * 2;
                 }
             }
-            (
-                InstType::ADD,
-                &Encoding::Memory(ea, size, _),
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-            ) => {
+            (InstType::JMP, &Encoding::Memory(ea, size, _), Encoding::Empty) => {
                 let address = self.translate_effective_address(ea);
-                self.store_add_memory(size, address, self.load_register(reg1));
-                clock = 16;
+                self.jump(self.load_memory(address, size) as u16);
+                clock = 18;
                 clock_ea = estimate_ea(ea);
-                if address % 2 == 1 {
-                    clock_transfer = 4 * 2;
+                if address.physical() % 2 == 1 {
+                    clock_transfer = 4;
                 }
             }
             (
-                InstType::ADD,
-                &Encoding::Memory(ea, size, _),
-                &Encoding::Operand(OperandEncoding::Immediate(val)),
+                InstType::CALL,
+                &Encoding::Operand(OperandEncoding::Jmp { offset, .. }),
+                Encoding::Empty,
             ) => {
-                let address = self.translate_effective_address(ea);
-                self.store_add_memory(size, address, val);
-                clock = 17;
-                clock_ea = estimate_ea(ea)
+                clock_transfer = self.estimate_stack_transfer();
+                self.push(self.ip as i16);
+                self.jump(self.ip.wrapping_add(offset as u16));
+                clock = 19;
             }
             (
-                InstType::SUB,
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Register(reg2)),
-            ) => self.store_sub(reg1, self.load_register(reg2)),
-            (
-                InstType::SUB,
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Immediate(val)),
-            ) => self.store_sub(reg1, val),
-            (
-                InstType::CMP,
-                &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Register(reg2)),
+                InstType::CALL,
+                &Encoding::Operand(OperandEncoding::Far { segment, offset }),
+                Encoding::Empty,
             ) => {
-                let from_reg = self.load_register(reg1);
-                let to_reg = self.load_register(reg1) - self.load_register(reg2);
-                self.update_flags(from_reg, to_reg);
-                self.update_sub_flags(from_reg, self.load_register(reg2))
+                clock_transfer = self.estimate_stack_transfer() * 2;
+                self.push(self.load_register(Register::CS));
+                self.push(self.ip as i16);
+                self.jump_far(segment, offset);
+                clock = 28;
             }
             (
-                InstType::CMP,
+                InstType::CALL,
                 &Encoding::Operand(OperandEncoding::Register(reg1)),
-                &Encoding::Operand(OperandEncoding::Immediate(val)),
+                Encoding::Empty,
             ) => {
-                let from_reg = self.load_register(reg1);
-                let to_reg = self.load_register(reg1) - val;
-                self.update_flags(from_reg, to_reg);
-                self.update_sub_flags(from_reg, val)
+                clock_transfer = self.estimate_stack_transfer();
+                let ip = self.load_register(reg1) as u16;
+                self.push(self.ip as i16);
+                self.jump(ip);
+                clock = 16;
+            }
+            (InstType::CALL, &Encoding::Memory(ea, _, OperandType::Far), Encoding::Empty) => {
+                let address = self.translate_effective_address(ea);
+                let (segment, offset) = self.load_far_pointer(address);
+                clock_transfer = self.estimate_stack_transfer() * 2;
+                if address.physical() % 2 == 1 {
+                    clock_transfer += 4 * 2;
+                }
+                self.push(self.load_register(Register::CS));
+                self.push(self.ip as i16);
+                self.jump_far(segment, offset);
+                clock = 37;
+                clock_ea = estimate_ea(ea);
+            }
+            (InstType::CALL, &Encoding::Memory(ea, size, _), Encoding::Empty) => {
+                let address = self.translate_effective_address(ea);
+                let ip = self.load_memory(address, size) as u16;
+                clock_transfer = self.estimate_stack_transfer();
+                if address.physical() % 2 == 1 {
+                    clock_transfer += 4;
+                }
+                self.push(self.ip as i16);
+                self.jump(ip);
+                clock = 21;
+                clock_ea = estimate_ea(ea);
             }
             (
-                InstType::JNZ,
-                &Encoding::Operand(OperandEncoding::Jmp { offset, .. }),
+                InstType::INT,
+                &Encoding::Operand(OperandEncoding::Immediate(vector)),
                 Encoding::Empty,
             ) => {
-                if !self.flags.is_zf() {
-                    self.ip = (self.ip as i16 + offset as i16) as u16;
+                clock_transfer = self.estimate_stack_transfer() * 3;
+                self.interrupt(vector as u8);
+                clock = 51;
+            }
+            (InstType::INT3, Encoding::Empty, Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer() * 3;
+                self.interrupt(3);
+                clock = 52;
+            }
+            (InstType::INTO, Encoding::Empty, Encoding::Empty) => {
+                if self.flags.is_of() {
+                    clock_transfer = self.estimate_stack_transfer() * 3;
+                    self.interrupt(4);
+                    clock = 53;
+                } else {
+                    clock = 4;
                 }
             }
+            (InstType::IRET, Encoding::Empty, Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer() * 3;
+                let offset = self.pop() as u16;
+                let segment = self.pop() as u16;
+                self.jump_far(segment, offset);
+                self.flags = Flags::from_word(self.pop());
+                clock = 24;
+            }
+            (InstType::CLC, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_cf(false);
+                clock = 2;
+            }
+            (InstType::STC, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_cf(true);
+                clock = 2;
+            }
+            (InstType::CMC, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_cf(!self.flags.is_cf());
+                clock = 2;
+            }
+            (InstType::CLD, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_df(false);
+                clock = 2;
+            }
+            (InstType::STD, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_df(true);
+                clock = 2;
+            }
+            (InstType::CLI, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_if(false);
+                clock = 2;
+            }
+            (InstType::STI, Encoding::Empty, Encoding::Empty) => {
+                self.flags = self.flags.with_if(true);
+                clock = 2;
+            }
+            (InstType::HLT, Encoding::Empty, Encoding::Empty) => {
+                // nothing can wake the cpu up, there are no external interrupts
+                self.halted = Some(from_ip);
+                clock = 2;
+            }
+            // test pin is never asserted, so the cpu doesn't wait
+            (InstType::WAIT, Encoding::Empty, Encoding::Empty) => {
+                clock = 3;
+            }
+            (InstType::NOP, Encoding::Empty, Encoding::Empty) => {
+                clock = 3;
+            }
+            (InstType::RET, Encoding::Empty, Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer();
+                let ip = self.pop() as u16;
+                self.jump(ip);
+                clock = 8;
+            }
             (
-                InstType::LOOP,
-                &Encoding::Operand(OperandEncoding::Jmp { offset, .. }),
+                InstType::RET,
+                &Encoding::Operand(OperandEncoding::Immediate(val)),
                 Encoding::Empty,
             ) => {
-                let new_cx = self.load_register(Register::CX) - 1;
-                self.store_register(Register::CX, new_cx);
-                if new_cx != 0 {
-                    self.ip = (self.ip as i16 + offset as i16) as u16;
-                }
+                clock_transfer = self.estimate_stack_transfer();
+                let ip = self.pop() as u16;
+                self.jump(ip);
+                self.release_stack(val);
+                clock = 12;
+            }
+            (InstType::RETF, Encoding::Empty, Encoding::Empty) => {
+                clock_transfer = self.estimate_stack_transfer() * 2;
+                let offset = self.pop() as u16;
+                let segment = self.pop() as u16;
+                self.jump_far(segment, offset);
+                clock = 18;
+            }
+            (
+                InstType::RETF,
+                &Encoding::Operand(OperandEncoding::Immediate(val)),
+                Encoding::Empty,
+            ) => {
+                clock_transfer = self.estimate_stack_transfer() * 2;
+                let offset = self.pop() as u16;
+                let segment = self.pop() as u16;
+                self.jump_far(segment, offset);
+                self.release_stack(val);
+                clock = 17;
             }
             _ => {
-                unimplemented!("unimplemented instruction {:?}", inst);
+                let kind = EmulatorErrorKind::UnsupportedInstruction(Box::new(inst));
+                return Err(self.fault(from_ip, kind));
+            }
+        };
+
+        // single step interrupt follows the instruction executed with the trap flag,
+        // interrupts clear the flag, so the handler itself isn't stepped
+        let mut trap_clock = 0;
+        if from_flags.is_tf() && self.flags.is_tf() {
+            clock_transfer += self.estimate_stack_transfer() * 3;
+            self.interrupt(1);
+            trap_clock = 50;
+        }
+        if let Some(fault) = self.memory_fault {
+            return Err(self.fault(from_ip, EmulatorErrorKind::MemoryFault(fault)));
+        }
+        self.steps += 1;
+        let lock_clock = if inst.lock { 2 } else { 0 };
+
+        let mut flag_update = None;
+        if self.flags != from_flags {
+            flag_update = Some((from_flags, self.flags));
+        }
+
+        let register_update = std::mem::take(&mut self.register_update);
+        let stack_update = self.stack_update.take();
+        let port_update = self.port_update.take();
+
+        // every write is traced, also the ones storing the same value
+        let mut memory_update: Vec<(SegmentedAddress, OperandSize, i16, i16)> = vec![];
+        for event in self.events.take() {
+            if let Event::MemoryWrite(address, size, from, to) = event {
+                memory_update.push((address, size, from, to));
+            }
+            match event {
+                Event::Register(reg, from, to) => {
+                    observer.register_write(reg, from as u16, to as u16)
+                }
+                Event::MemoryRead(address, size, val) => {
+                    observer.memory_read(address, size, size.mask(val) as u16)
+                }
+                Event::MemoryWrite(address, size, from, to) => observer.memory_write(
+                    address,
+                    size,
+                    size.mask(from) as u16,
+                    size.mask(to) as u16,
+                ),
+                Event::Interrupt(vector, address) => observer.interrupt(vector, address),
+            }
+        }
+        if let Some((from, to)) = flag_update {
+            observer.flags_change(from, to);
+        }
+
+        // TODO Step struct is a bad idea for interpretation loop,
+        // but I don't want to spend much time to do it properly
+        let step = Step {
+            inst,
+            bytes,
+            ip: (from_ip, self.cs_ip()),
+            flags: flag_update,
+            register: register_update,
+            memory: memory_update,
+            stack: stack_update,
+            port: port_update,
+            clock: Clock {
+                value: clock + lock_clock + trap_clock,
+                transfer: clock_transfer,
+                ea: clock_ea,
+            },
+        };
+        observer.after_instruction(&step);
+        Ok(Some(step))
+    }
+
+    // evaluates condition of the conditional jump or loop,
+    // returns whether it's taken and clocks for taken and not taken cases
+    fn branch(&mut self, t: &InstType) -> (bool, u16, u16) {
+        let f = self.flags;
+        match t {
+            InstType::JO => (f.is_of(), 16, 4),
+            InstType::JNO => (!f.is_of(), 16, 4),
+            InstType::JB => (f.is_cf(), 16, 4),
+            InstType::JNB => (!f.is_cf(), 16, 4),
+            InstType::JE => (f.is_zf(), 16, 4),
+            InstType::JNZ => (!f.is_zf(), 16, 4),
+            InstType::JBE => (f.is_cf() || f.is_zf(), 16, 4),
+            InstType::JA => (!f.is_cf() && !f.is_zf(), 16, 4),
+            InstType::JS => (f.is_sf(), 16, 4),
+            InstType::JNS => (!f.is_sf(), 16, 4),
+            InstType::JP => (f.is_pf(), 16, 4),
+            InstType::JNP => (!f.is_pf(), 16, 4),
+            InstType::JL => (f.is_sf() != f.is_of(), 16, 4),
+            InstType::JNL => (f.is_sf() == f.is_of(), 16, 4),
+            InstType::JLE => (f.is_zf() || f.is_sf() != f.is_of(), 16, 4),
+            InstType::JG => (!f.is_zf() && f.is_sf() == f.is_of(), 16, 4),
+            InstType::JCXZ => (self.load_register(Register::CX) == 0, 18, 6),
+            InstType::LOOP => (self.decrement_cx() != 0, 17, 5),
+            InstType::LOOPZ => (self.decrement_cx() != 0 && f.is_zf(), 18, 6),
+            InstType::LOOPNZ => (self.decrement_cx() != 0 && !f.is_zf(), 19, 5),
+            _ => unreachable!(),
+        }
+    }
+
+    fn decrement_cx(&mut self) -> i16 {
+        let cx = self.load_register(Register::CX).wrapping_sub(1);
+        self.store_register(Register::CX, cx);
+        cx
+    }
+
+    fn ss_sp(&self) -> SegmentedAddress {
+        SegmentedAddress::new(
+            self.load_register(Register::SS) as u16,
+            self.load_register(Register::SP) as u16,
+        )
+    }
+
+    fn store_sp(&mut self, sp: i16, op: StackOp) {
+        let from_sp = self.load_register(Register::SP);
+        self.registers = self.registers.store(Register::SP, sp);
+        self.record(Event::Register(Register::SP, from_sp, sp));
+        let ((from_sp, _), mut ops) = self
+            .stack_update
+            .take()
+            .unwrap_or(((from_sp, from_sp), vec![]));
+        ops.push(op);
+        self.stack_update = Some(((from_sp, sp), ops));
+    }
+
+    fn push(&mut self, val: i16) {
+        let sp = self.load_register(Register::SP).wrapping_sub(2);
+        self.store_sp(sp, StackOp::Push(val));
+        self.store_memory(self.ss_sp(), val, OperandSize::Word);
+    }
+
+    fn pop(&mut self) -> i16 {
+        let val = self.load_memory(self.ss_sp(), OperandSize::Word);
+        let sp = self.load_register(Register::SP).wrapping_add(2);
+        self.store_sp(sp, StackOp::Pop(val));
+        val
+    }
+
+    // discards the given number of bytes from the stack, e.g. arguments of `ret 4`
+    fn release_stack(&mut self, bytes: i16) {
+        let sp = self.load_register(Register::SP);
+        let from_sp = self
+            .stack_update
+            .as_ref()
+            .map_or(sp, |((from, _), _)| *from);
+        let ops = self.stack_update.take().map_or(vec![], |(_, ops)| ops);
+        let to_sp = sp.wrapping_add(bytes);
+        self.registers = self.registers.store(Register::SP, to_sp);
+        self.record(Event::Register(Register::SP, sp, to_sp));
+        let sp = to_sp;
+        self.stack_update = Some(((from_sp, sp), ops));
+    }
+
+    // pushes flags, cs and ip, then jumps through the vector table at physical 0
+    fn interrupt(&mut self, vector: u8) {
+        self.record(Event::Interrupt(vector, self.cs_ip()));
+        self.push(self.flags.to_word());
+        self.flags = self.flags.with_if(false).with_tf(false);
+        self.push(self.load_register(Register::CS));
+        self.push(self.ip as i16);
+        let (segment, offset) = self.load_far_pointer(SegmentedAddress::new(0, vector as u16 * 4));
+        self.jump_far(segment, offset);
+    }
+
+    fn jump(&mut self, ip: u16) {
+        self.ip = ip;
+        self.jumped = true;
+    }
+
+    fn jump_far(&mut self, segment: u16, offset: u16) {
+        self.store_register(Register::CS, segment as i16);
+        self.jump(offset);
+    }
+
+    // doubleword pointer is stored as offset followed by segment
+    fn load_far_pointer(&self, address: SegmentedAddress) -> (u16, u16) {
+        let offset = self.load_memory(address, OperandSize::Word) as u16;
+        let segment = self.load_memory(address.add(2), OperandSize::Word) as u16;
+        (segment, offset)
+    }
+
+    // word transfer to the stack takes 4 more clocks if sp is odd
+    fn estimate_stack_transfer(&self) -> u8 {
+        if self.ss_sp().physical() % 2 == 1 {
+            4
+        } else {
+            0
+        }
+    }
+
+    fn translate_effective_address(&self, ea: EffectiveAddress) -> SegmentedAddress {
+        let segment = self.load_register(ea.segment()) as u16;
+        let base = match ea.register {
+            RegisterAddress::Empty => 0,
+            RegisterAddress::BXSI => self
+                .load_register(Register::BX)
+                .wrapping_add(self.load_register(Register::SI)),
+            RegisterAddress::BXDI => self
+                .load_register(Register::BX)
+                .wrapping_add(self.load_register(Register::DI)),
+            RegisterAddress::BPSI => self
+                .load_register(Register::BP)
+                .wrapping_add(self.load_register(Register::SI)),
+            RegisterAddress::BPDI => self
+                .load_register(Register::BP)
+                .wrapping_add(self.load_register(Register::DI)),
+            RegisterAddress::SI => self.load_register(Register::SI),
+            RegisterAddress::DI => self.load_register(Register::DI),
+            RegisterAddress::BX => self.load_register(Register::BX),
+            RegisterAddress::DirectBP => self.load_register(Register::BP),
+        };
+        SegmentedAddress::new(segment, ea.disp.wrapping_add(base) as u16)
+    }
+
+    fn load(&self, encoding: &Encoding) -> i16 {
+        match *encoding {
+            Encoding::Operand(OperandEncoding::Register(reg)) => self.load_register(reg),
+            Encoding::Operand(OperandEncoding::Accumulator(size)) => {
+                self.load_register(Register::accumulator(size))
+            }
+            Encoding::Operand(OperandEncoding::Immediate(val)) => val,
+            Encoding::Memory(ea, size, _) => {
+                self.load_memory(self.translate_effective_address(ea), size)
             }
+            _ => unreachable!("can't load {:?}", encoding),
+        }
+    }
+
+    fn store(&mut self, encoding: &Encoding, val: i16) {
+        match *encoding {
+            Encoding::Operand(OperandEncoding::Register(reg)) => self.store_register(reg, val),
+            Encoding::Operand(OperandEncoding::Accumulator(size)) => {
+                self.store_register(Register::accumulator(size), val)
+            }
+            Encoding::Memory(ea, size, _) => {
+                self.store_memory(self.translate_effective_address(ea), val, size)
+            }
+            _ => unreachable!("can't store to {:?}", encoding),
+        }
+    }
+
+    // clocks, effective address clocks and odd address penalty of the memory operand
+    // https://edge.edx.org/c4x/BITSPilani/EEE231/asset/8086_family_Users_Manual_1_.pdf 2-51
+    fn estimate_two_operands(&self, inst: &Inst) -> (u16, u8, u8) {
+        use OperandKind::*;
+        let (clock, transfers) = match (&inst.t, inst.lhs.kind(), inst.rhs.kind()) {
+            (InstType::MOV, Accumulator, Memory) | (InstType::MOV, Memory, Accumulator) => (10, 1),
+            (InstType::MOV, Register | Accumulator, Register | Accumulator) => (2, 0),
+            (InstType::MOV, Register, Memory) => (8, 1),
+            (InstType::MOV, Memory, Register) => (9, 1),
+            (InstType::MOV, Register | Accumulator, Immediate) => (4, 0),
+            (InstType::MOV, Memory, Immediate) => (10, 1),
+            (InstType::CMP | InstType::TEST, Memory, Register) => (9, 1),
+            (InstType::CMP, Memory, Immediate) => (10, 1),
+            (InstType::TEST, Register, Immediate) => (5, 0),
+            (InstType::TEST, Memory, Immediate) => (11, 1),
+            (_, Register | Accumulator, Register | Accumulator) => (3, 0),
+            (_, Register | Accumulator, Memory) => (9, 1),
+            (_, Memory, Register) => (16, 2),
+            (_, Register | Accumulator, Immediate) => (4, 0),
+            (_, Memory, Immediate) => (17, 2),
+            _ => (0, 0),
         };
 
-        self.ip += inst.length as u16;
+        // direct address of the accumulator forms doesn't take effective address time
+        let is_accumulator =
+            matches!(inst.lhs.kind(), Accumulator) || matches!(inst.rhs.kind(), Accumulator);
+        let (ea, transfer) = [&inst.lhs, &inst.rhs]
+            .into_iter()
+            .find(|encoding| matches!(encoding.kind(), Memory))
+            .map_or((0, 0), |encoding| self.estimate_memory(encoding, transfers));
+        (clock, if is_accumulator { 0 } else { ea }, transfer)
+    }
+
+    // clocks of the register or memory operand of single operand instructions
+    fn estimate_one_operand(
+        &self,
+        encoding: &Encoding,
+        reg_clock: u16,
+        mem_clock: u16,
+        transfers: u8,
+    ) -> (u16, u8, u8) {
+        match encoding {
+            Encoding::Memory(..) => {
+                let (ea, transfer) = self.estimate_memory(encoding, transfers);
+                (mem_clock, ea, transfer)
+            }
+            _ => (reg_clock, 0, 0),
+        }
+    }
+
+    // effective address clocks and odd address penalty for the number of transfers
+    fn estimate_memory(&self, encoding: &Encoding, transfers: u8) -> (u8, u8) {
+        match *encoding {
+            Encoding::Memory(ea, size, _) => (
+                estimate_ea(ea),
+                self.estimate_transfer(self.translate_effective_address(ea), size) * transfers,
+            ),
+            _ => (0, 0),
+        }
+    }
 
-        let mut flag_update = None;
-        if self.flags != from_flags {
-            flag_update = Some((from_flags, self.flags));
+    // word transfer to or from an odd address takes 4 more clocks
+    fn estimate_transfer(&self, address: SegmentedAddress, size: OperandSize) -> u8 {
+        match size {
+            OperandSize::Word if address.physical() % 2 == 1 => 4,
+            _ => 0,
         }
+    }
 
-        let register_update = self.register_update;
-        self.register_update = None;
+    // dx addressed ports take less time, word at an odd port costs the same as in memory
+    fn estimate_port(&self, size: OperandSize, port: &Encoding) -> (u16, u8) {
+        let clock = match port.kind() {
+            OperandKind::Register => 8,
+            _ => 10,
+        };
+        let odd = self.load(port) as u16 % 2 == 1;
+        let transfer = match size {
+            OperandSize::Word if odd => 4,
+            _ => 0,
+        };
+        (clock, transfer)
+    }
 
-        // TODO Step struct is a bad idea for interpretation loop,
-        // but I don't want to spend much time to do it properly
-        Some(Step {
-            inst,
-            ip: (from_ip, self.ip),
-            flags: flag_update,
-            register: register_update,
-            clock: Clock {
-                value: clock,
-                transfer: clock_transfer,
-                ea: clock_ea,
-            },
-        })
+    fn load_port(&mut self, port: u16, size: OperandSize) -> Result<i16, UnclaimedPortError> {
+        let val = match size {
+            OperandSize::Byte => self.ports.read(port)? as i16,
+            OperandSize::Word => self.ports.read_word(port)? as i16,
+        };
+        self.port_update = Some(PortOp::In(port, size, val));
+        Ok(val)
     }
 
-    fn translate_effective_address(&self, ea: EffectiveAddress) -> u16 {
-        let address = ea.disp
-            + match ea.register {
-                RegisterAddress::Empty => 0,
-                RegisterAddress::BXSI => {
-                    self.load_register(Register::BX) + self.load_register(Register::SI)
-                }
-                RegisterAddress::BXDI => {
-                    self.load_register(Register::BX) + self.load_register(Register::DI)
-                }
-                RegisterAddress::BPSI => {
-                    self.load_register(Register::BP) + self.load_register(Register::SI)
-                }
-                RegisterAddress::BPDI => {
-                    self.load_register(Register::BP) + self.load_register(Register::DI)
-                }
-                RegisterAddress::SI => self.load_register(Register::SI),
-                RegisterAddress::DI => self.load_register(Register::DI),
-                RegisterAddress::BX => self.load_register(Register::BX),
-                RegisterAddress::DirectBP => self.load_register(Register::BP),
-            };
-        address as u16
+    fn store_port(
+        &mut self,
+        port: u16,
+        val: i16,
+        size: OperandSize,
+    ) -> Result<(), UnclaimedPortError> {
+        match size {
+            OperandSize::Byte => self.ports.write(port, val as u8)?,
+            OperandSize::Word => self.ports.write_word(port, val as u16)?,
+        };
+        self.port_update = Some(PortOp::Out(port, size, val));
+        Ok(())
     }
 
     fn load_register(&self, reg: Register) -> i16 {
@@ -406,93 +1601,373 @@ impl Emulator {
     }
 
     fn store_register(&mut self, reg: Register, val: i16) {
-        self.register_update = Some((reg, self.registers.load(reg), val));
+        let from = self.registers.load(reg);
         self.registers = self.registers.store(reg, val);
+        let to = self.registers.load(reg);
+        self.record(Event::Register(reg, from, to));
+        // repeated writes to the same register are traced as a single change
+        match self.register_update.iter_mut().find(|(r, ..)| *r == reg) {
+            Some((_, _, last)) => *last = to,
+            None => self.register_update.push((reg, from, to)),
+        }
+    }
+
+    fn record(&self, event: Event) {
+        self.events.borrow_mut().push(event);
     }
 
-    fn store_memory(&mut self, address: u16, val: i16, size: OperandSize) {
-        self.memory[address as usize] = (val as u16 & 0xFF) as u8;
+    fn store_memory(&mut self, address: SegmentedAddress, val: i16, size: OperandSize) {
+        let from = self.read_memory(address, size);
+        self.record(Event::MemoryWrite(address, size, from, val));
+        self.write_memory(address.physical(), (val as u16 & 0xFF) as u8);
         if let OperandSize::Word = size {
-            self.memory[address as usize + 1] = ((val as u16 >> 8) & 0xFF) as u8;
+            self.write_memory(address.add(1).physical(), ((val as u16 >> 8) & 0xFF) as u8);
         };
     }
 
-    fn load_memory(&self, address: u16, size: OperandSize) -> i16 {
-        let mut val = self.memory[address as usize] as u16;
+    // the instruction keeps executing after a refused write, it fails at the end
+    fn write_memory(&mut self, address: usize, val: u8) {
+        if let Err(fault) = self.memory.write(address, val) {
+            self.memory_fault.get_or_insert(fault);
+        }
+    }
+
+    fn load_memory(&self, address: SegmentedAddress, size: OperandSize) -> i16 {
+        let val = self.read_memory(address, size);
+        self.record(Event::MemoryRead(address, size, val));
+        val
+    }
+
+    // reads without recording the access
+    fn read_memory(&self, address: SegmentedAddress, size: OperandSize) -> i16 {
+        let mut val = self.memory.read(address.physical()) as u16;
         if let OperandSize::Word = size {
-            val |= (self.memory[address as usize + 1] as u16) << 8;
+            val |= (self.memory.read(address.add(1).physical()) as u16) << 8;
         };
         val as i16
     }
 
-    fn store_add_register(&mut self, reg: Register, val: i16) {
-        let from_reg = self.load_register(reg);
-        self.store_register(reg, from_reg + val);
-        let to_reg = self.load_register(reg);
+    // adds operands of the given width, updates all arithmetic flags
+    fn add(&mut self, size: OperandSize, lhs: i16, rhs: i16) -> i16 {
+        self.add_carry(size, lhs, rhs, false)
+    }
+
+    fn add_carry(&mut self, size: OperandSize, lhs: i16, rhs: i16, carry: bool) -> i16 {
+        let (lhs, rhs) = (size.mask(lhs), size.mask(rhs));
+        let result = lhs + rhs + carry as u32;
+        let overflow = (lhs ^ result) & (rhs ^ result) & size.sign() != 0;
+        self.update_arith_flags(size, lhs, rhs, result, result > size.mask(-1), overflow)
+    }
 
-        self.update_flags(from_reg, to_reg);
-        self.update_add_flags(from_reg, val);
+    // subtracts operands of the given width, updates all arithmetic flags
+    fn sub(&mut self, size: OperandSize, lhs: i16, rhs: i16) -> i16 {
+        self.sub_borrow(size, lhs, rhs, false)
     }
 
-    fn store_add_memory(&mut self, size: OperandSize, address: u16, val: i16) {
-        let from = self.load_memory(address, size);
-        let to = from + val;
-        self.store_memory(address, to, size);
+    fn sub_borrow(&mut self, size: OperandSize, lhs: i16, rhs: i16, borrow: bool) -> i16 {
+        let (lhs, rhs) = (size.mask(lhs), size.mask(rhs));
+        let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow as u32);
+        let overflow = (lhs ^ rhs) & (lhs ^ result) & size.sign() != 0;
+        let carry = lhs < rhs + borrow as u32;
+        self.update_arith_flags(size, lhs, rhs, result, carry, overflow)
+    }
 
-        self.update_flags(from, to);
-        self.update_add_flags(from, val);
+    fn update_arith_flags(
+        &mut self,
+        size: OperandSize,
+        lhs: u32,
+        rhs: u32,
+        result: u32,
+        carry: bool,
+        overflow: bool,
+    ) -> i16 {
+        self.flags = self
+            .flags
+            .with_cf(carry)
+            .with_of(overflow)
+            .with_af((lhs ^ rhs ^ result) & 0x10 != 0);
+        self.update_flags(size, result)
     }
 
-    fn store_sub(&mut self, reg: Register, val: i16) {
-        let from_reg = self.load_register(reg);
-        self.store_register(reg, self.load_register(reg) - val);
-        let to_reg = self.load_register(reg);
+    // shifts and rotates bit by bit as 8086 does, the count isn't masked
+    fn shift(&mut self, t: &InstType, size: OperandSize, val: i16, count: u8) -> i16 {
+        let sign = size.sign();
+        let mut val = size.mask(val);
+        let mut flags = self.flags;
+        for _ in 0..count {
+            let msb = val & sign != 0;
+            let lsb = val & 1 != 0;
+            (val, flags) = match t {
+                InstType::SHL => (val << 1, flags.with_cf(msb)),
+                InstType::SHR => (val >> 1, flags.with_cf(lsb)),
+                InstType::SAR => ((val >> 1) | (val & sign), flags.with_cf(lsb)),
+                InstType::ROL => ((val << 1) | lsb_of(msb), flags.with_cf(msb)),
+                InstType::ROR => ((val >> 1) | sign_of(lsb, sign), flags.with_cf(lsb)),
+                InstType::RCL => ((val << 1) | lsb_of(flags.is_cf()), flags.with_cf(msb)),
+                InstType::RCR => (
+                    (val >> 1) | sign_of(flags.is_cf(), sign),
+                    flags.with_cf(lsb),
+                ),
+                _ => unreachable!(),
+            };
+            val &= size.mask(-1);
+
+            let msb = val & sign != 0;
+            let next_msb = val & (sign >> 1) != 0;
+            let overflow = match t {
+                InstType::SHL | InstType::ROL | InstType::RCL => msb != flags.is_cf(),
+                InstType::SHR => val & (sign >> 1) != 0 && !msb,
+                InstType::SAR => false,
+                _ => msb != next_msb,
+            };
+            flags = flags.with_of(overflow);
+        }
 
-        self.update_flags(from_reg, to_reg);
-        self.update_sub_flags(from_reg, val);
+        self.flags = flags;
+        if count == 0 {
+            return val as i16;
+        }
+        match t {
+            InstType::SHL | InstType::SHR | InstType::SAR => self.update_flags(size, val),
+            _ => val as i16,
+        }
     }
 
-    fn update_flags(&mut self, from_val: i16, to_val: i16) {
-        if to_val < 0 {
-            self.flags = self.flags.set_sf();
-        } else {
-            self.flags = self.flags.unset_sf();
+    // single iteration of a string instruction, si and di follow the direction flag
+    fn string(&mut self, t: &InstType, lhs: &Encoding, rhs: &Encoding) {
+        let size = lhs.size();
+        match t {
+            InstType::CMPS | InstType::SCAS => {
+                self.sub(size, self.load(lhs), self.load(rhs));
+            }
+            _ => self.store(lhs, self.load(rhs)),
         }
-        if to_val == 0 {
-            self.flags = self.flags.set_zf();
-        } else {
-            self.flags = self.flags.unset_zf();
+
+        let step = size.size() as i16 / 8;
+        let delta = if self.flags.is_df() { -step } else { step };
+        for encoding in [lhs, rhs] {
+            if let Encoding::Memory(ea, ..) = encoding {
+                let reg = match ea.register {
+                    RegisterAddress::SI => Register::SI,
+                    _ => Register::DI,
+                };
+                self.store_register(reg, self.load_register(reg).wrapping_add(delta));
+            }
         }
-        if (to_val & 0xFF).count_ones() % 2 == 0 {
-            self.flags = self.flags.set_pf();
-        } else {
-            self.flags = self.flags.unset_pf();
+    }
+
+    // corrects al after packed bcd addition or subtraction
+    fn decimal_adjust(&mut self, t: &InstType) {
+        let al = self.load_register(Register::AL) as u8;
+        let (af, cf) = (self.flags.is_af(), self.flags.is_cf());
+        let mut correction = 0;
+        let mut carry = false;
+        if al & 0xF > 9 || af {
+            correction = 0x06;
+            carry = cf
+                || match t {
+                    InstType::DAA => al > 0xF9,
+                    _ => al < 0x06,
+                };
         }
-        if (to_val > 0 && from_val < 0) || (to_val < 0 && from_val > 0) {
-            self.flags = self.flags.set_cf();
-        } else {
-            self.flags = self.flags.unset_cf();
+        if al > 0x99 || cf {
+            correction += 0x60;
+            carry = true;
+        }
+
+        // silicon sets overflow as if the correction was added or subtracted
+        if self.opt.undocumented_flags {
+            match t {
+                InstType::DAA => self.add(OperandSize::Byte, al as i16, correction),
+                _ => self.sub(OperandSize::Byte, al as i16, correction),
+            };
         }
+        let result = match t {
+            InstType::DAA => al.wrapping_add(correction as u8),
+            _ => al.wrapping_sub(correction as u8),
+        };
+        self.flags = self.flags.with_cf(carry).with_af(correction & 0x0F != 0);
+        let al = self.update_flags(OperandSize::Byte, result as u32);
+        self.store_register(Register::AL, al);
     }
-    fn update_add_flags(&mut self, from_val: i16, val: i16) {
-        if (from_val & 0xF) + (val & 0xF) > 0xF {
-            self.flags = self.flags.set_af();
-        } else {
-            self.flags = self.flags.unset_af();
+
+    // corrects al after unpacked bcd addition or subtraction, carrying into ah
+    fn ascii_adjust(&mut self, t: &InstType) {
+        let al = self.load_register(Register::AL) as u8;
+        let ah = self.load_register(Register::AH) as u8;
+        let adjust = al & 0xF > 9 || self.flags.is_af();
+        let correction = if adjust { 0x06 } else { 0 };
+
+        // silicon sets the rest of flags from the correction of al
+        if self.opt.undocumented_flags {
+            match t {
+                InstType::AAA => self.add(OperandSize::Byte, al as i16, correction),
+                _ => self.sub(OperandSize::Byte, al as i16, correction),
+            };
         }
+        let (al, ah) = match t {
+            InstType::AAA => (
+                al.wrapping_add(correction as u8),
+                ah.wrapping_add(adjust as u8),
+            ),
+            _ => (
+                al.wrapping_sub(correction as u8),
+                ah.wrapping_sub(adjust as u8),
+            ),
+        };
+        self.flags = self.flags.with_cf(adjust).with_af(adjust);
+        self.store_register(Register::AX, (ah as i16) << 8 | (al & 0xF) as i16);
     }
-    fn update_sub_flags(&mut self, from_val: i16, val: i16) {
-        if (from_val & 0xF) - (val & 0xF) < 0 {
-            self.flags = self.flags.set_af();
-        } else {
-            self.flags = self.flags.unset_af();
+
+    // product goes to ax or dx:ax, carry and overflow tell if its upper half is significant
+    fn multiply(&mut self, t: &InstType, size: OperandSize, val: i16) {
+        let extend = |val| extend(t, size, val);
+        let product = extend(self.load_register(Register::accumulator(size))) * extend(val);
+        let significant = product != extend(product as i16);
+        self.flags = self.flags.with_cf(significant).with_of(significant);
+        if let OperandSize::Word = size {
+            self.store_register(Register::DX, (product >> 16) as i16);
+        }
+        self.store_register(Register::AX, product as i16);
+    }
+
+    // divides ax or dx:ax, returns the quotient or none on divide error
+    fn divide(&mut self, t: &InstType, size: OperandSize, val: i16) -> Option<i16> {
+        let ax = self.load_register(Register::AX);
+        let dividend = match (t, size) {
+            (InstType::IDIV, OperandSize::Byte) => ax as i64,
+            (_, OperandSize::Byte) => ax as u16 as i64,
+            (t, OperandSize::Word) => {
+                let dx = self.load_register(Register::DX);
+                let dividend = ((dx as u16 as u32) << 16) | ax as u16 as u32;
+                match t {
+                    InstType::IDIV => dividend as i32 as i64,
+                    _ => dividend as i64,
+                }
+            }
+        };
+        let divisor = extend(t, size, val);
+        if divisor == 0 {
+            return None;
+        }
+
+        // 8086 doesn't produce the most negative quotient
+        let (quotient, remainder) = (dividend / divisor, dividend % divisor);
+        let max = match t {
+            InstType::IDIV => size.sign() as i64 - 1,
+            _ => size.mask(-1) as i64,
+        };
+        let min = match t {
+            InstType::IDIV => -max,
+            _ => 0,
+        };
+        if !(min..=max).contains(&quotient) {
+            return None;
+        }
+
+        match size {
+            OperandSize::Byte => self.store_register(
+                Register::AX,
+                ((remainder as i16) << 8) | (quotient as i16 & 0xFF),
+            ),
+            OperandSize::Word => {
+                self.store_register(Register::DX, remainder as i16);
+                self.store_register(Register::AX, quotient as i16);
+            }
+        }
+        Some(quotient as i16)
+    }
+
+    // logical operations clear carry and overflow, the rest is set from the result
+    fn logic(&mut self, size: OperandSize, result: i16) -> i16 {
+        self.flags = self.flags.with_cf(false).with_of(false).with_af(false);
+        self.update_flags(size, size.mask(result))
+    }
+
+    // updates sign, zero and parity flags of the result, returns it truncated to the width
+    fn update_flags(&mut self, size: OperandSize, result: u32) -> i16 {
+        let result = result & size.mask(-1);
+        self.flags = self
+            .flags
+            .with_sf(result & size.sign() != 0)
+            .with_zf(result == 0)
+            .with_pf((result & 0xFF).count_ones().is_multiple_of(2));
+        result as i16
+    }
+}
+
+// json is written by hand to keep the crate free of dependencies
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
         }
     }
+    json.push('"');
+    json
+}
+
+fn json_address(address: SegmentedAddress) -> String {
+    format!(
+        r#"{{"segment":{},"offset":{},"physical":{}}}"#,
+        address.segment,
+        address.offset,
+        address.physical()
+    )
+}
+
+fn json_port(direction: &str, port: u16, size: OperandSize, val: i16) -> String {
+    format!(
+        r#"{{"direction":{},"port":{},"size":{},"value":{}}}"#,
+        json_string(direction),
+        port,
+        size.size() / 8,
+        size.mask(val)
+    )
+}
+
+fn json_error(e: &EmulatorError) -> String {
+    let kind = match e.kind {
+        EmulatorErrorKind::UnsupportedInstruction(_) => "unsupported_instruction",
+        EmulatorErrorKind::InvalidOpcode(_) => "invalid_opcode",
+        EmulatorErrorKind::PortFault(_) => "port_fault",
+        EmulatorErrorKind::MemoryFault(_) => "memory_fault",
+        EmulatorErrorKind::StepLimit(_) => "step_limit",
+    };
+    format!(
+        r#"{{"kind":{},"message":{}}}"#,
+        json_string(kind),
+        json_string(&e.to_string())
+    )
+}
+
+fn json_change(name: &str, from: u16, to: u16) -> String {
+    format!(
+        r#"{{"name":{},"from":{},"to":{}}}"#,
+        json_string(name),
+        from,
+        to
+    )
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    // json object per step followed by the final state object, also on failure
+    Jsonl,
 }
 
 #[derive(Default, Clone)]
 pub struct TracerOptions {
+    pub format: TraceFormat,
     pub with_ip: bool,
+    pub with_segments: bool,
+    pub with_memory: bool,
     pub with_trace: bool,
     pub with_estimate: bool,
     pub dump_path: String,
@@ -513,30 +1988,45 @@ impl Tracer {
         }
     }
 
-    pub fn run(&mut self, emulator: &mut Emulator) {
+    // memory is dumped even if emulation fails
+    pub fn run(&mut self, emulator: &mut Emulator) -> Result<Stop, EmulatorError> {
+        let result = emulator.run_with(self);
+        // the text report of a failure is up to the caller
         if self.opt.with_trace {
-            while let Some(step) = emulator.step() {
-                self.trace(step);
+            match (self.opt.format, &result) {
+                (TraceFormat::Text, Ok(_)) => self.print(emulator),
+                (TraceFormat::Text, Err(_)) => {}
+                (TraceFormat::Jsonl, result) => self.print_json(emulator, result),
             }
-            self.print(emulator);
-        } else {
-            emulator.run();
         }
 
         if !self.opt.dump_path.is_empty() {
             self.dump(emulator);
         }
+        result
     }
 
-    fn trace(&mut self, step: Step) {
+    fn trace(&mut self, step: &Step) {
         use std::io::Write;
         let mut sink = std::io::stdout();
         let mut write_trace = |msg| write!(sink, "{}", msg).unwrap();
         let fmt_flags = |from, to| format!(" flags:{}->{}", from, to);
         let fmt_reg = |reg, from, to| format!(" {}:{:#x}->{:#x}", reg, from, to);
-        let fmt_ip = |from, to| format!(" ip:{:#x}->{:#x}", from, to);
+        let fmt_stack = |op| match op {
+            StackOp::Push(val) => format!(" push:{:#x}", val),
+            StackOp::Pop(val) => format!(" pop:{:#x}", val),
+        };
+        let fmt_port = |op: PortOp| format!(" {}", op);
+        let fmt_ip = |from: SegmentedAddress, to: SegmentedAddress| {
+            format!(" ip:{:#x}->{:#x}", from.offset, to.offset)
+        };
+        let fmt_cs_ip = |from, to| format!(" cs:ip:{}->{}", from, to);
+        let fmt_memory = |address, size: OperandSize, from, to| {
+            let (from, to) = (size.mask(from), size.mask(to));
+            format!(" {}[{}]:{:#x}->{:#x}", size, address, from, to)
+        };
         let mut fmt_clock = |clock: Clock| {
-            let inc = clock.value + clock.ea + clock.transfer;
+            let inc = clock.value + clock.ea as u16 + clock.transfer as u16;
             self.clocks += inc as u32;
             let mut fmt = format!(" Clocks: +{} = {}", inc, self.clocks);
             if clock.ea != 0 && clock.transfer != 0 {
@@ -555,16 +2045,33 @@ impl Tracer {
         if self.opt.with_estimate {
             write_trace(fmt_clock(step.clock));
         }
-        match step.register {
-            Some((reg, from, to)) if from != to => {
+        for &(reg, from, to) in &step.register {
+            if from != to {
                 self.registers.insert(reg);
                 write_trace(fmt_reg(reg, from, to));
             }
-            _ => {}
+        }
+        if let Some(((from, to), ops)) = &step.stack {
+            self.registers.insert(Register::SP);
+            write_trace(fmt_reg(Register::SP, *from, *to));
+            for &op in ops {
+                write_trace(fmt_stack(op));
+            }
+        }
+        if let Some(op) = step.port {
+            write_trace(fmt_port(op));
+        }
+        if self.opt.with_memory {
+            for &(address, size, from, to) in &step.memory {
+                write_trace(fmt_memory(address, size, from, to));
+            }
         }
         if self.opt.with_ip {
             write_trace(fmt_ip(step.ip.0, step.ip.1));
         }
+        if self.opt.with_segments {
+            write_trace(fmt_cs_ip(step.ip.0, step.ip.1));
+        }
         if let Some((from, to)) = step.flags {
             write_trace(fmt_flags(from, to));
         }
@@ -596,6 +2103,14 @@ impl Tracer {
             ));
         }
 
+        if self.opt.with_segments {
+            write_trace(format!("   cs:ip: {}\n", emulator.cs_ip()));
+        }
+
+        if let Some(address) = emulator.halted {
+            write_trace(format!("    halt: hlt at {}\n", address));
+        }
+
         if emulator.flags != Flags(0) {
             write_trace(format!("   flags: {}", emulator.flags));
         }
@@ -603,11 +2118,763 @@ impl Tracer {
         write_trace("\n".to_string());
     }
 
+    fn trace_json(&mut self, step: &Step) {
+        let clocks = step.clocks();
+        self.clocks += clocks as u32;
+
+        let mut registers = step
+            .register
+            .iter()
+            .filter(|(_, from, to)| from != to)
+            .map(|&(reg, from, to)| json_change(&reg.to_string(), from as u16, to as u16))
+            .collect::<Vec<_>>();
+        if let Some(((from, to), _)) = step.stack {
+            registers.push(json_change("sp", from as u16, to as u16));
+        }
+        let memory = step
+            .memory
+            .iter()
+            .map(|&(address, size, from, to)| {
+                format!(
+                    r#"{{"address":{},"size":{},"from":{},"to":{}}}"#,
+                    json_address(address),
+                    size.size() / 8,
+                    size.mask(from),
+                    size.mask(to)
+                )
+            })
+            .collect::<Vec<_>>();
+        let port = match step.port {
+            Some(PortOp::In(port, size, val)) => json_port("in", port, size, val),
+            Some(PortOp::Out(port, size, val)) => json_port("out", port, size, val),
+            None => "null".to_string(),
+        };
+        let flags = match step.flags {
+            Some((from, to)) => format!(
+                r#"{{"from":{},"to":{}}}"#,
+                json_string(&from.to_string()),
+                json_string(&to.to_string())
+            ),
+            None => "null".to_string(),
+        };
+        let bytes = step
+            .bytes
+            .iter()
+            .map(|byte| byte.to_string())
+            .collect::<Vec<_>>();
+
+        println!(
+            r#"{{"type":"step","address":{},"bytes":[{}],"inst":{},"next":{},"registers":[{}],"flags":{},"memory":[{}],"port":{},"clocks":{{"value":{},"ea":{},"transfer":{},"total":{}}},"cycles":{}}}"#,
+            json_address(step.ip.0),
+            bytes.join(","),
+            json_string(&step.inst.to_string()),
+            json_address(step.ip.1),
+            registers.join(","),
+            flags,
+            memory.join(","),
+            port,
+            step.clock.value,
+            step.clock.ea,
+            step.clock.transfer,
+            clocks,
+            self.clocks,
+        );
+    }
+
+    // on failure the state points at the failed instruction
+    fn print_json(&mut self, emulator: &Emulator, result: &Result<Stop, EmulatorError>) {
+        use Register::*;
+        let registers = [AX, BX, CX, DX, SP, BP, SI, DI, ES, CS, SS, DS]
+            .into_iter()
+            .map(|reg| format!(r#""{}":{}"#, reg, emulator.register(reg)))
+            .collect::<Vec<_>>();
+        let halted = emulator.halted.map_or("null".to_string(), json_address);
+        let (stop, error) = match result {
+            Ok(Stop::Halted(_)) => ("halted", "null".to_string()),
+            Ok(Stop::EndOfProgram(_)) => ("end_of_program", "null".to_string()),
+            Err(e) => ("error", json_error(e)),
+        };
+        println!(
+            r#"{{"type":"final","stop":{},"error":{},"registers":{{{}}},"ip":{},"flags":{},"flags_word":{},"halted":{},"cycles":{}}}"#,
+            json_string(stop),
+            error,
+            registers.join(","),
+            json_address(emulator.cs_ip()),
+            json_string(&emulator.flags.to_string()),
+            emulator.flags.bits(),
+            halted,
+            self.clocks,
+        );
+    }
+
     fn dump(&mut self, emulator: &Emulator) {
         use std::io::Write;
         let mut sink =
             std::fs::File::create(self.opt.dump_path.clone()).expect("can't create file");
-        sink.write_all(emulator.memory.as_ref())
-            .expect("can't dump");
+        sink.write_all(&emulator.memory.dump()).expect("can't dump");
+    }
+}
+
+// text trace of the executed instructions
+impl Observer for Tracer {
+    fn after_instruction(&mut self, step: &Step) {
+        if self.opt.with_trace {
+            match self.opt.format {
+                TraceFormat::Text => self.trace(step),
+                TraceFormat::Jsonl => self.trace_json(step),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // loads the code at 0100:0000
+    fn load(code: &[u8], registers: &[(Register, u16)]) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.registers = emulator.registers.store(Register::CS, 0x100);
+        emulator.load_program(code);
+        for &(reg, val) in registers {
+            emulator.registers = emulator.registers.store(reg, val as i16);
+        }
+        emulator
+    }
+
+    // runs the code loaded at 0100:0000 until ip leaves it
+    fn run(code: &[u8], registers: &[(Register, u16)]) -> Emulator {
+        let mut emulator = load(code, registers);
+        emulator.run().unwrap();
+        emulator
+    }
+
+    // runs the code with CF set
+    fn run_with_carry(code: &[u8], registers: &[(Register, u16)]) -> Emulator {
+        let mut emulator = load(code, registers);
+        emulator.flags = emulator.flags.with_cf(true);
+        emulator.run().unwrap();
+        emulator
+    }
+
+    fn register(emulator: &Emulator, reg: Register) -> u16 {
+        emulator.load_register(reg) as u16
+    }
+
+    fn flags(emulator: &Emulator) -> String {
+        emulator.flags.to_string()
+    }
+
+    #[test]
+    fn logic_clears_carry_and_overflow() {
+        // and ax, bx
+        let emulator = run_with_carry(
+            &[0x21, 0xd8],
+            &[(Register::AX, 0xf0f0), (Register::BX, 0xff00)],
+        );
+        assert_eq!(register(&emulator, Register::AX), 0xf000);
+        assert_eq!(flags(&emulator), "PS");
+
+        // or al, 15
+        let emulator = run_with_carry(&[0x0c, 0x0f], &[(Register::AX, 0x00f0)]);
+        assert_eq!(register(&emulator, Register::AX), 0x00ff);
+        assert_eq!(flags(&emulator), "PS");
+    }
+
+    #[test]
+    fn test_only_sets_flags() {
+        // test al, 0x80
+        let emulator = run(&[0xa8, 0x80], &[(Register::AX, 0x0080)]);
+        assert_eq!(register(&emulator, Register::AX), 0x0080);
+        assert_eq!(flags(&emulator), "S");
+    }
+
+    #[test]
+    fn xor_with_itself_zeroes() {
+        // xor ax, ax
+        let emulator = run_with_carry(&[0x31, 0xc0], &[(Register::AX, 0x1234)]);
+        assert_eq!(register(&emulator, Register::AX), 0);
+        assert_eq!(flags(&emulator), "PZ");
+    }
+
+    #[test]
+    fn not_keeps_flags() {
+        // not ax
+        let emulator = run_with_carry(&[0xf7, 0xd0], &[(Register::AX, 0x00ff)]);
+        assert_eq!(register(&emulator, Register::AX), 0xff00);
+        assert_eq!(flags(&emulator), "C");
+    }
+
+    #[test]
+    fn shift_by_cl_sets_carry_and_overflow() {
+        // shl ax, cl
+        let emulator = run(&[0xd3, 0xe0], &[(Register::AX, 0x8001), (Register::CX, 1)]);
+        assert_eq!(register(&emulator, Register::AX), 0x0002);
+        assert_eq!(flags(&emulator), "CO");
+
+        // sar ax, cl
+        let emulator = run(&[0xd3, 0xf8], &[(Register::AX, 0x8005), (Register::CX, 1)]);
+        assert_eq!(register(&emulator, Register::AX), 0xc002);
+        assert_eq!(flags(&emulator), "CS");
+
+        // shl ax, cl, the last bit shifted out goes to CF
+        let emulator = run(&[0xd3, 0xe0], &[(Register::AX, 0x1234), (Register::CX, 4)]);
+        assert_eq!(register(&emulator, Register::AX), 0x2340);
+        assert!(emulator.flags.is_cf());
+    }
+
+    #[test]
+    fn rotate_by_cl_sets_carry_and_overflow() {
+        // rol al, cl
+        let emulator = run(&[0xd2, 0xc0], &[(Register::AX, 0x0081), (Register::CX, 1)]);
+        assert_eq!(register(&emulator, Register::AX), 0x0003);
+        assert_eq!(flags(&emulator), "CO");
+
+        // rcr ax, cl
+        let emulator = run_with_carry(&[0xd3, 0xd8], &[(Register::AX, 1), (Register::CX, 1)]);
+        assert_eq!(register(&emulator, Register::AX), 0x8000);
+        assert_eq!(flags(&emulator), "CO");
+    }
+
+    #[test]
+    fn shift_by_zero_keeps_flags() {
+        // shl ax, cl
+        let emulator = run_with_carry(&[0xd3, 0xe0], &[(Register::AX, 0x8000), (Register::CX, 0)]);
+        assert_eq!(register(&emulator, Register::AX), 0x8000);
+        assert_eq!(flags(&emulator), "C");
+    }
+
+    fn write_bytes(emulator: &mut Emulator, address: usize, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            emulator.memory.write(address + i, byte).unwrap();
+        }
+    }
+
+    fn read_word(emulator: &Emulator, address: usize) -> u16 {
+        u16::from_le_bytes([
+            read_byte(emulator, address),
+            read_byte(emulator, address + 1),
+        ])
+    }
+
+    // runs the code with the divide error handler at 0200:0000, it halts at once
+    fn run_divide(code: &[u8], registers: &[(Register, u16)]) -> Emulator {
+        let mut emulator = load(code, registers);
+        write_bytes(&mut emulator, 0, &[0x00, 0x00, 0x00, 0x02]);
+        write_bytes(&mut emulator, 0x2000, &[0xf4]);
+        emulator.run().unwrap();
+        emulator
+    }
+
+    #[test]
+    fn idiv_rounds_toward_zero() {
+        // idiv bx, dx:ax = -7
+        let emulator = run_divide(
+            &[0xf7, 0xfb],
+            &[
+                (Register::AX, 0xfff9),
+                (Register::DX, 0xffff),
+                (Register::BX, 2),
+            ],
+        );
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 2));
+        assert_eq!(register(&emulator, Register::AX), 0xfffd);
+        assert_eq!(register(&emulator, Register::DX), 0xffff);
+
+        // idiv bl, the smallest quotient
+        let emulator = run_divide(&[0xf6, 0xfb], &[(Register::AX, 0xff81), (Register::BX, 1)]);
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 2));
+        assert_eq!(register(&emulator, Register::AX), 0x0081);
+    }
+
+    #[test]
+    fn idiv_out_of_range_is_divide_error() {
+        // idiv bl, -128 isn't produced
+        let emulator = run_divide(&[0xf6, 0xfb], &[(Register::AX, 0xff80), (Register::BX, 1)]);
+        let handler = SegmentedAddress::new(0x200, 0);
+        assert_eq!(emulator.stopped(), Some(Stop::Halted(handler)));
+        assert_eq!(register(&emulator, Register::AX), 0xff80);
+
+        // idiv bl, 256 / 2
+        let emulator = run_divide(&[0xf6, 0xfb], &[(Register::AX, 0x0100), (Register::BX, 2)]);
+        let handler = SegmentedAddress::new(0x200, 0);
+        assert_eq!(emulator.stopped(), Some(Stop::Halted(handler)));
+        assert_eq!(register(&emulator, Register::AX), 0x0100);
+    }
+
+    #[test]
+    fn divide_by_zero_interrupts() {
+        // div bx
+        let emulator = run_divide(&[0xf7, 0xf3], &[(Register::AX, 1)]);
+        let handler = SegmentedAddress::new(0x200, 0);
+        assert_eq!(emulator.stopped(), Some(Stop::Halted(handler)));
+        assert_eq!(register(&emulator, Register::AX), 1);
+        // ip, cs and flags are pushed, the return address is after div
+        assert_eq!(register(&emulator, Register::SP), 0xfffa);
+        assert_eq!(read_word(&emulator, 0xfffa), 2);
+        assert_eq!(read_word(&emulator, 0xfffc), 0x100);
+    }
+
+    #[test]
+    fn adc_carries_into_high_word() {
+        // add ax, cx; adc dx, bx
+        let emulator = run(
+            &[0x01, 0xc8, 0x11, 0xda],
+            &[
+                (Register::AX, 0xffff),
+                (Register::DX, 0x7fff),
+                (Register::CX, 1),
+            ],
+        );
+        assert_eq!(register(&emulator, Register::AX), 0);
+        assert_eq!(register(&emulator, Register::DX), 0x8000);
+        assert_eq!(flags(&emulator), "PASO");
+    }
+
+    #[test]
+    fn sbb_borrows_from_high_word() {
+        // sub ax, cx; sbb dx, bx
+        let emulator = run(
+            &[0x29, 0xc8, 0x19, 0xda],
+            &[(Register::DX, 1), (Register::CX, 1)],
+        );
+        assert_eq!(register(&emulator, Register::AX), 0xffff);
+        assert_eq!(register(&emulator, Register::DX), 0);
+        assert_eq!(flags(&emulator), "PZ");
+
+        let emulator = run(&[0x29, 0xc8, 0x19, 0xda], &[(Register::CX, 1)]);
+        assert_eq!(register(&emulator, Register::AX), 0xffff);
+        assert_eq!(register(&emulator, Register::DX), 0xffff);
+        assert_eq!(flags(&emulator), "CPAS");
+    }
+
+    #[test]
+    fn daa_adjusts_packed_addition() {
+        // add al, 0x45; daa
+        let emulator = run(&[0x04, 0x45, 0x27], &[(Register::AX, 0x38)]);
+        assert_eq!(register(&emulator, Register::AX), 0x83);
+        let flags = emulator.flags;
+        assert!(!flags.is_cf() && flags.is_af() && flags.is_sf());
+        assert!(!flags.is_zf() && !flags.is_pf());
+
+        // add al, 1; daa, 99 + 1 carries out
+        let emulator = run(&[0x04, 0x01, 0x27], &[(Register::AX, 0x99)]);
+        assert_eq!(register(&emulator, Register::AX), 0);
+        let flags = emulator.flags;
+        assert!(flags.is_cf() && flags.is_af() && !flags.is_sf());
+        assert!(flags.is_zf() && flags.is_pf());
+    }
+
+    #[test]
+    fn das_adjusts_packed_subtraction() {
+        // sub al, 0x38; das
+        let emulator = run(&[0x2c, 0x38, 0x2f], &[(Register::AX, 0x45)]);
+        assert_eq!(register(&emulator, Register::AX), 0x07);
+        let flags = emulator.flags;
+        assert!(!flags.is_cf() && flags.is_af() && !flags.is_sf());
+        assert!(!flags.is_zf() && !flags.is_pf());
+
+        // sub al, 0x45; das, 38 - 45 borrows
+        let emulator = run(&[0x2c, 0x45, 0x2f], &[(Register::AX, 0x38)]);
+        assert_eq!(register(&emulator, Register::AX), 0x93);
+        let flags = emulator.flags;
+        assert!(flags.is_cf() && !flags.is_af() && flags.is_sf());
+        assert!(!flags.is_zf() && flags.is_pf());
+    }
+
+    #[test]
+    fn aaa_carries_into_ah() {
+        // add al, 5; aaa
+        let emulator = run(&[0x04, 0x05, 0x37], &[(Register::AX, 0x0008)]);
+        assert_eq!(register(&emulator, Register::AX), 0x0103);
+        assert!(emulator.flags.is_cf() && emulator.flags.is_af());
+
+        // add al, 1; aaa, no adjustment clears the high nibble only
+        let emulator = run(&[0x04, 0x01, 0x37], &[(Register::AX, 0x0032)]);
+        assert_eq!(register(&emulator, Register::AX), 0x0003);
+        assert!(!emulator.flags.is_cf() && !emulator.flags.is_af());
+    }
+
+    #[test]
+    fn aas_borrows_from_ah() {
+        // sub al, 5; aas
+        let emulator = run(&[0x2c, 0x05, 0x3f], &[(Register::AX, 0x0102)]);
+        assert_eq!(register(&emulator, Register::AX), 0x0007);
+        assert!(emulator.flags.is_cf() && emulator.flags.is_af());
+    }
+
+    fn read_byte(emulator: &Emulator, address: usize) -> u8 {
+        emulator.memory.read(address)
+    }
+
+    #[test]
+    fn rep_stops_when_cx_runs_out() {
+        // rep stosb
+        let emulator = run(
+            &[0xf3, 0xaa],
+            &[
+                (Register::AX, 0x55),
+                (Register::CX, 3),
+                (Register::DI, 0x3000),
+            ],
+        );
+        assert_eq!(register(&emulator, Register::CX), 0);
+        assert_eq!(register(&emulator, Register::DI), 0x3003);
+        let stored: Vec<u8> = (0x3000..0x3004).map(|a| read_byte(&emulator, a)).collect();
+        assert_eq!(stored, [0x55, 0x55, 0x55, 0]);
+
+        // rep stosb with cx = 0 doesn't store anything
+        let emulator = run(
+            &[0xf3, 0xaa],
+            &[(Register::AX, 0x55), (Register::DI, 0x3000)],
+        );
+        assert_eq!(register(&emulator, Register::DI), 0x3000);
+        assert_eq!(read_byte(&emulator, 0x3000), 0);
+    }
+
+    #[test]
+    fn repe_stops_on_mismatch() {
+        // repe cmpsb
+        let mut emulator = load(
+            &[0xf3, 0xa6],
+            &[
+                (Register::CX, 4),
+                (Register::SI, 0x3000),
+                (Register::DI, 0x3100),
+            ],
+        );
+        write_bytes(&mut emulator, 0x3000, b"abcx");
+        write_bytes(&mut emulator, 0x3100, b"abdx");
+        emulator.run().unwrap();
+        assert_eq!(register(&emulator, Register::CX), 1);
+        assert_eq!(register(&emulator, Register::SI), 0x3003);
+        assert_eq!(register(&emulator, Register::DI), 0x3103);
+        assert!(!emulator.flags.is_zf());
+
+        // equal strings run until cx runs out
+        let mut emulator = load(
+            &[0xf3, 0xa6],
+            &[
+                (Register::CX, 4),
+                (Register::SI, 0x3000),
+                (Register::DI, 0x3100),
+            ],
+        );
+        write_bytes(&mut emulator, 0x3000, b"abcx");
+        write_bytes(&mut emulator, 0x3100, b"abcx");
+        emulator.run().unwrap();
+        assert_eq!(register(&emulator, Register::CX), 0);
+        assert!(emulator.flags.is_zf());
+    }
+
+    #[test]
+    fn repne_stops_on_match() {
+        // repne scasb
+        let mut emulator = load(&[0xf2, 0xae], &[(Register::CX, 10), (Register::DI, 0x3000)]);
+        write_bytes(&mut emulator, 0x3000, b"hello\0");
+        emulator.run().unwrap();
+        assert_eq!(register(&emulator, Register::CX), 4);
+        assert_eq!(register(&emulator, Register::DI), 0x3006);
+        assert!(emulator.flags.is_zf());
+    }
+
+    #[test]
+    fn repeated_rep_prefix_is_skipped() {
+        // rep rep stosb; hlt
+        let mut emulator = load(
+            &[0xf3, 0xf3, 0xaa, 0xf4],
+            &[
+                (Register::AX, 0x55),
+                (Register::CX, 2),
+                (Register::DI, 0x3000),
+            ],
+        );
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 3)));
+        assert_eq!(register(&emulator, Register::CX), 0);
+        let stored: Vec<u8> = (0x3000..0x3003).map(|a| read_byte(&emulator, a)).collect();
+        assert_eq!(stored, [0x55, 0x55, 0]);
+    }
+
+    #[test]
+    fn rep_with_segment_override_in_either_order() {
+        // rep es: movsb; hlt and es: rep movsb; hlt
+        for code in [[0xf3, 0x26, 0xa4, 0xf4], [0x26, 0xf3, 0xa4, 0xf4]] {
+            let mut emulator = load(
+                &code,
+                &[
+                    (Register::CX, 2),
+                    (Register::ES, 0x300),
+                    (Register::DI, 0x10),
+                ],
+            );
+            write_bytes(&mut emulator, 0x3000, b"ab");
+            let stop = emulator.run().unwrap();
+            assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 3)));
+            assert_eq!(register(&emulator, Register::CX), 0);
+            assert_eq!(register(&emulator, Register::SI), 2);
+            assert_eq!(register(&emulator, Register::DI), 0x12);
+            let copied: Vec<u8> = (0x3010..0x3012).map(|a| read_byte(&emulator, a)).collect();
+            assert_eq!(copied, b"ab");
+        }
+    }
+
+    #[test]
+    fn repeated_segment_prefix_is_skipped() {
+        // es: es: mov ax, [bx]; hlt
+        let mut emulator = load(&[0x26, 0x26, 0x8b, 0x07, 0xf4], &[(Register::ES, 0x300)]);
+        write_bytes(&mut emulator, 0x3000, &[0x34, 0x12]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 4)));
+        assert_eq!(register(&emulator, Register::AX), 0x1234);
+        // the second es: prefix didn't run as pop es
+        assert_eq!(register(&emulator, Register::ES), 0x300);
+        assert_eq!(register(&emulator, Register::SP), 0);
+    }
+
+    #[test]
+    fn interrupt_handler_returns_with_iret() {
+        // int 0x21; hlt
+        let mut emulator = load(&[0xcd, 0x21, 0xf4], &[]);
+        // vector 0x21 points to 0200:0000, mov ax, 0x1234; stc; iret
+        write_bytes(&mut emulator, 0x21 * 4, &[0x00, 0x00, 0x00, 0x02]);
+        write_bytes(&mut emulator, 0x2000, &[0xb8, 0x34, 0x12, 0xf9, 0xcf]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 2)));
+        assert_eq!(register(&emulator, Register::AX), 0x1234);
+        assert_eq!(register(&emulator, Register::SP), 0);
+        // iret restores the flags pushed by int
+        assert!(!emulator.flags.is_cf());
+    }
+
+    #[test]
+    fn store_to_rom_is_ignored() {
+        // mov byte [0x3000], 0x22
+        let mut emulator = load(&[0xc6, 0x06, 0x00, 0x30, 0x22], &[]);
+        emulator.map_rom(0x3000, vec![0x11]);
+        emulator.run().unwrap();
+        assert_eq!(read_byte(&emulator, 0x3000), 0x11);
+    }
+
+    // refuses every write
+    struct ReadOnly;
+
+    impl MemoryDevice for ReadOnly {
+        fn read(&self, _offset: usize) -> u8 {
+            0
+        }
+
+        fn write(&mut self, _offset: usize, _val: u8) -> Result<(), crate::memory::DeviceError> {
+            Err(crate::memory::DeviceError)
+        }
+    }
+
+    #[test]
+    fn refused_write_rolls_back_instruction() {
+        // push ax, the stack is in the device
+        let mut emulator = load(
+            &[0x50],
+            &[(Register::SS, 0x300), (Register::SP, 2), (Register::AX, 1)],
+        );
+        emulator.map_device(0x3000..0x3002, ReadOnly);
+        let e = emulator.step().unwrap_err();
+        let EmulatorErrorKind::MemoryFault(fault) = e.kind else {
+            panic!("unexpected error {}", e);
+        };
+        assert_eq!(fault.address, 0x3000);
+        assert_eq!(e.state.cs_ip(), SegmentedAddress::new(0x100, 0));
+        assert_eq!(e.state.register(Register::SP), 2);
+        assert_eq!(register(&emulator, Register::SP), 2);
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 0));
+        assert_eq!(emulator.steps, 0);
+    }
+
+    #[test]
+    fn step_executes_one_instruction() {
+        // mov ax, 1; rep stosb; hlt
+        let mut emulator = load(
+            &[0xb8, 0x01, 0x00, 0xf3, 0xaa, 0xf4],
+            &[(Register::CX, 2), (Register::DI, 0x3000)],
+        );
+        let inst = emulator.step().unwrap().unwrap();
+        assert_eq!(inst.to_string(), "mov ax, 1");
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 3));
+        assert_eq!(emulator.register(Register::AX), 1);
+
+        // a repeated string instruction iterates once per step
+        let inst = emulator.step().unwrap().unwrap();
+        assert_eq!(inst.to_string(), "rep stosb");
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 3));
+        assert_eq!(emulator.register(Register::CX), 1);
+        emulator.step().unwrap().unwrap();
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 5));
+        assert_eq!(emulator.stopped(), None);
+
+        emulator.step().unwrap().unwrap();
+        let hlt = SegmentedAddress::new(0x100, 5);
+        assert_eq!(emulator.stopped(), Some(Stop::Halted(hlt)));
+        assert_eq!(emulator.halted(), Some(hlt));
+        assert!(emulator.step().unwrap().is_none());
+    }
+
+    #[test]
+    fn data_is_loaded_without_moving_program() {
+        // mov al, [0x3000]
+        let mut emulator = load(&[0xa0, 0x00, 0x30], &[]);
+        emulator.load_data_at(SegmentedAddress::new(0x300, 0), &[0x42]);
+        assert_eq!(emulator.cs_ip(), SegmentedAddress::new(0x100, 0));
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::EndOfProgram(SegmentedAddress::new(0x100, 3)));
+        assert_eq!(emulator.register(Register::AX), 0x42);
+    }
+
+    #[test]
+    fn flags_are_set_before_run() {
+        // jb +1; hlt; hlt
+        let mut emulator = load(&[0x72, 0x01, 0xf4, 0xf4], &[]);
+        emulator.set_flags(Flags::default().with_cf(true));
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 3)));
+        assert!(emulator.flags().is_cf());
+    }
+
+    // records the observed events as text
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Observer for Recorder {
+        fn before_instruction(&mut self, _address: SegmentedAddress, inst: &Inst) {
+            self.0.push(format!("before {}", inst));
+        }
+
+        fn after_instruction(&mut self, step: &Step) {
+            self.0.push(format!("after {}", step.inst()));
+        }
+
+        fn register_write(&mut self, reg: Register, from: u16, to: u16) {
+            self.0.push(format!("{}:{:#x}->{:#x}", reg, from, to));
+        }
+
+        fn memory_write(
+            &mut self,
+            address: SegmentedAddress,
+            _size: OperandSize,
+            from: u16,
+            to: u16,
+        ) {
+            self.0.push(format!("[{}]:{:#x}->{:#x}", address, from, to));
+        }
+
+        fn flags_change(&mut self, from: Flags, to: Flags) {
+            self.0.push(format!("flags:{}->{}", from, to));
+        }
+    }
+
+    #[test]
+    fn observer_gets_events_between_before_and_after() {
+        // push ax; sub ax, ax
+        let mut emulator = load(&[0x50, 0x29, 0xc0], &[(Register::AX, 1), (Register::SP, 2)]);
+        let mut recorder = Recorder::default();
+        emulator.run_with(&mut recorder).unwrap();
+        assert_eq!(
+            recorder.0,
+            [
+                "before push ax",
+                "sp:0x2->0x0",
+                "[0000:0000]:0x0->0x1",
+                "after push ax",
+                "before sub ax, ax",
+                "ax:0x1->0x0",
+                "flags:->PZ",
+                "after sub ax, ax",
+            ]
+        );
+    }
+
+    #[test]
+    fn step_lists_every_memory_write() {
+        // mov word [0x3000], 0
+        let mut emulator = load(&[0xc7, 0x06, 0x00, 0x30, 0x00, 0x00], &[]);
+        let step = emulator.execute(&mut ()).unwrap().unwrap();
+        let address = SegmentedAddress::new(0, 0x3000);
+        assert_eq!(step.memory, [(address, OperandSize::Word, 0, 0)]);
+    }
+
+    #[test]
+    fn inc_dec_clocks_depend_on_encoding() {
+        // inc ax; inc ax with FF /0; inc al; dec cx; dec cx with FF /1; inc word [0x3000]
+        let mut emulator = load(
+            &[
+                0x40, 0xff, 0xc0, 0xfe, 0xc0, 0x49, 0xff, 0xc9, 0xff, 0x06, 0x00, 0x30,
+            ],
+            &[],
+        );
+        let clocks: Vec<u16> = (0..6)
+            .map(|_| emulator.execute(&mut ()).unwrap().unwrap().clocks())
+            .collect();
+        assert_eq!(clocks, [2, 3, 3, 2, 3, 21]);
+    }
+
+    #[test]
+    fn port_access_is_traced_with_its_size() {
+        // out dx, ax; in al, dx
+        let mut emulator = load(&[0xef, 0xec], &[(Register::AX, 0xff), (Register::DX, 0x61)]);
+        let step = emulator.execute(&mut ()).unwrap().unwrap();
+        assert_eq!(step.port.unwrap().to_string(), "out:word:0x61=0xff");
+        let step = emulator.execute(&mut ()).unwrap().unwrap();
+        assert_eq!(step.port.unwrap().to_string(), "in:byte:0x61=0xff");
+    }
+
+    #[test]
+    fn truncated_last_instruction_is_invalid_opcode() {
+        // nop; mov ax, with the high byte of the immediate missing
+        let mut emulator = load(&[0x90, 0xb8, 0x01], &[]);
+        let e = emulator.run().unwrap_err();
+        let EmulatorErrorKind::InvalidOpcode(DecodeError::Truncated { bytes, .. }) = e.kind else {
+            panic!("unexpected error {}", e);
+        };
+        assert_eq!(bytes, [0xb8, 0x01]);
+        assert_eq!(e.state.cs_ip(), SegmentedAddress::new(0x100, 1));
+        assert_eq!(register(&emulator, Register::AX), 0);
+    }
+
+    #[test]
+    fn jump_out_of_image_keeps_executing() {
+        // jmp 0x10 lands outside of the image, hlt there
+        let mut emulator = load(&[0xeb, 0x0e], &[]);
+        emulator.load_data_at(SegmentedAddress::new(0x100, 0x10), &[0xf4]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 0x10)));
+    }
+
+    #[test]
+    fn end_of_image_is_its_own_stop() {
+        // nop
+        let mut emulator = load(&[0x90], &[]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::EndOfProgram(SegmentedAddress::new(0x100, 1)));
+    }
+
+    #[test]
+    fn jump_to_end_of_image_keeps_executing() {
+        // jmp 2 lands right after the image, mov ax, 1; hlt there
+        let mut emulator = load(&[0xeb, 0x00], &[]);
+        emulator.load_data_at(SegmentedAddress::new(0x100, 2), &[0xb8, 0x01, 0x00, 0xf4]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 5)));
+        assert_eq!(register(&emulator, Register::AX), 1);
+
+        // call 3 lands right after the image, hlt there
+        let mut emulator = load(&[0xe8, 0x00, 0x00], &[(Register::SP, 0x100)]);
+        emulator.load_data_at(SegmentedAddress::new(0x100, 3), &[0xf4]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::Halted(SegmentedAddress::new(0x100, 3)));
+        assert_eq!(register(&emulator, Register::SP), 0xfe);
+
+        // jz 2 isn't taken, so it falls through to the end of the image
+        let mut emulator = load(&[0x74, 0x00], &[]);
+        emulator.load_data_at(SegmentedAddress::new(0x100, 2), &[0xf4]);
+        let stop = emulator.run().unwrap();
+        assert_eq!(stop, Stop::EndOfProgram(SegmentedAddress::new(0x100, 2)));
     }
 }
@@ -0,0 +1,179 @@
+use std::ops::RangeInclusive;
+
+/// Device attached to a range of I/O ports, `in` and `out` instructions are dispatched to it.
+/// Word accesses are split into two byte accesses unless the device handles them itself.
+pub trait PortDevice {
+    fn read(&mut self, port: u16) -> u8;
+    fn write(&mut self, port: u16, val: u8);
+
+    fn read_word(&mut self, port: u16) -> u16 {
+        let low = self.read(port) as u16;
+        low | (self.read(port.wrapping_add(1)) as u16) << 8
+    }
+
+    fn write_word(&mut self, port: u16, val: u16) {
+        self.write(port, val as u8);
+        self.write(port.wrapping_add(1), (val >> 8) as u8);
+    }
+}
+
+/// What happens on access to a port no device is attached to
+#[derive(Debug, Default, Clone, Copy)]
+pub enum UnclaimedPort {
+    /// reads return 0, writes are dropped
+    Ignore,
+    /// reads return 0xFF as the floating data bus does, writes are dropped
+    #[default]
+    Float,
+    /// access is an error
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UnclaimedPortError {
+    pub port: u16,
+}
+
+#[derive(Default)]
+pub struct PortBus {
+    devices: Vec<(RangeInclusive<u16>, Box<dyn PortDevice>)>,
+    unclaimed: UnclaimedPort,
+}
+
+impl std::fmt::Debug for PortBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PortBus")
+            .field(
+                "devices",
+                &self
+                    .devices
+                    .iter()
+                    .map(|(ports, _)| ports)
+                    .collect::<Vec<_>>(),
+            )
+            .field("unclaimed", &self.unclaimed)
+            .finish()
+    }
+}
+
+impl PortBus {
+    pub fn new(unclaimed: UnclaimedPort) -> Self {
+        Self {
+            devices: vec![],
+            unclaimed,
+        }
+    }
+
+    // the device attached last wins on overlapping ranges
+    pub fn attach(&mut self, ports: RangeInclusive<u16>, device: Box<dyn PortDevice>) {
+        self.devices.push((ports, device));
+    }
+
+    fn find(&mut self, port: u16) -> Option<usize> {
+        self.devices
+            .iter()
+            .rposition(|(ports, _)| ports.contains(&port))
+    }
+
+    pub fn read(&mut self, port: u16) -> Result<u8, UnclaimedPortError> {
+        match self.find(port) {
+            Some(idx) => Ok(self.devices[idx].1.read(port)),
+            None => match self.unclaimed {
+                UnclaimedPort::Ignore => Ok(0),
+                UnclaimedPort::Float => Ok(0xFF),
+                UnclaimedPort::Error => Err(UnclaimedPortError { port }),
+            },
+        }
+    }
+
+    pub fn write(&mut self, port: u16, val: u8) -> Result<(), UnclaimedPortError> {
+        match self.find(port) {
+            Some(idx) => {
+                self.devices[idx].1.write(port, val);
+                Ok(())
+            }
+            None => match self.unclaimed {
+                UnclaimedPort::Error => Err(UnclaimedPortError { port }),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    // both bytes of the word go to the same device if it claims them
+    pub fn read_word(&mut self, port: u16) -> Result<u16, UnclaimedPortError> {
+        let next = port.wrapping_add(1);
+        match (self.find(port), self.find(next)) {
+            (Some(idx), Some(next_idx)) if idx == next_idx => {
+                Ok(self.devices[idx].1.read_word(port))
+            }
+            _ => Ok(self.read(port)? as u16 | (self.read(next)? as u16) << 8),
+        }
+    }
+
+    pub fn write_word(&mut self, port: u16, val: u16) -> Result<(), UnclaimedPortError> {
+        let next = port.wrapping_add(1);
+        match (self.find(port), self.find(next)) {
+            (Some(idx), Some(next_idx)) if idx == next_idx => {
+                self.devices[idx].1.write_word(port, val);
+                Ok(())
+            }
+            _ => {
+                self.write(port, val as u8)?;
+                self.write(next, (val >> 8) as u8)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // remembers the last written byte and reads it back
+    struct Latch(u8);
+
+    impl PortDevice for Latch {
+        fn read(&mut self, _port: u16) -> u8 {
+            self.0
+        }
+
+        fn write(&mut self, _port: u16, val: u8) {
+            self.0 = val;
+        }
+    }
+
+    #[test]
+    fn unclaimed_port_policies() {
+        let mut bus = PortBus::new(UnclaimedPort::Ignore);
+        assert_eq!(bus.read(0x60).unwrap(), 0);
+        assert_eq!(bus.read_word(0x60).unwrap(), 0);
+        assert!(bus.write(0x60, 1).is_ok());
+
+        let mut bus = PortBus::new(UnclaimedPort::Float);
+        assert_eq!(bus.read(0x60).unwrap(), 0xFF);
+        assert_eq!(bus.read_word(0x60).unwrap(), 0xFFFF);
+        assert!(bus.write_word(0x60, 1).is_ok());
+
+        let mut bus = PortBus::new(UnclaimedPort::Error);
+        assert_eq!(bus.read(0x60).unwrap_err().port, 0x60);
+        assert_eq!(bus.write(0x61, 1).unwrap_err().port, 0x61);
+    }
+
+    #[test]
+    fn attached_device_claims_its_ports() {
+        let mut bus = PortBus::new(UnclaimedPort::Error);
+        bus.attach(0x60..=0x60, Box::new(Latch(0)));
+        bus.write(0x60, 0x42).unwrap();
+        assert_eq!(bus.read(0x60).unwrap(), 0x42);
+        // the high byte of the word falls on an unclaimed port
+        assert_eq!(bus.read_word(0x60).unwrap_err().port, 0x61);
+    }
+
+    #[test]
+    fn last_attached_device_wins() {
+        let mut bus = PortBus::new(UnclaimedPort::Float);
+        bus.attach(0x60..=0x61, Box::new(Latch(1)));
+        bus.attach(0x61..=0x61, Box::new(Latch(2)));
+        assert_eq!(bus.read_word(0x60).unwrap(), 0x0201);
+    }
+}
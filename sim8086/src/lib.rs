@@ -1,3 +1,6 @@
 pub mod ast;
 pub mod decoder;
 pub mod emulator;
+pub mod io;
+pub mod memory;
+pub mod observer;
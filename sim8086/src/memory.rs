@@ -0,0 +1,193 @@
+use std::ops::Range;
+
+/// Size of the 8086 physical address space
+pub const MEMORY_SIZE: usize = 1024 * 1024;
+
+/// Device mapped into a region of the address space, e.g. a video buffer.
+/// Addresses are offsets from the start of the region.
+/// Reads can't fail, a device returns whatever its data bus holds, e.g. 0xFF.
+pub trait MemoryDevice {
+    fn read(&self, offset: usize) -> u8;
+    fn write(&mut self, offset: usize, val: u8) -> Result<(), DeviceError>;
+}
+
+/// Write the device refused, e.g. to a read-only register
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceError;
+
+/// Physical address of the write a device refused
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryFault {
+    pub address: usize,
+}
+
+enum Region {
+    Rom(Vec<u8>),
+    Device(Box<dyn MemoryDevice>),
+}
+
+/// Physical address space, RAM unless a region is mapped over it
+pub struct MemoryBus {
+    ram: Vec<u8>,
+    regions: Vec<(Range<usize>, Region)>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self {
+            ram: vec![0; MEMORY_SIZE],
+            regions: vec![],
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regions = self
+            .regions
+            .iter()
+            .map(|(range, region)| match region {
+                Region::Rom(_) => format!("rom {:05x}..{:05x}", range.start, range.end),
+                Region::Device(_) => format!("device {:05x}..{:05x}", range.start, range.end),
+            })
+            .collect::<Vec<_>>();
+        f.debug_struct("MemoryBus")
+            .field("regions", &regions)
+            .finish()
+    }
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the region mapped last wins on overlapping ranges
+    pub fn map_rom(&mut self, start: usize, data: Vec<u8>) {
+        let end = (start + data.len()).min(MEMORY_SIZE);
+        self.regions.push((start..end, Region::Rom(data)));
+    }
+
+    pub fn map_device(&mut self, range: Range<usize>, device: Box<dyn MemoryDevice>) {
+        self.regions.push((range, Region::Device(device)));
+    }
+
+    fn find(&self, address: usize) -> Option<usize> {
+        self.regions
+            .iter()
+            .rposition(|(range, _)| range.contains(&address))
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        let address = address % MEMORY_SIZE;
+        match self.find(address) {
+            Some(idx) => {
+                let (range, region) = &self.regions[idx];
+                match region {
+                    Region::Rom(data) => data[address - range.start],
+                    Region::Device(device) => device.read(address - range.start),
+                }
+            }
+            None => self.ram[address],
+        }
+    }
+
+    // writes to rom are ignored as on real hardware, so only a device can fail it
+    pub fn write(&mut self, address: usize, val: u8) -> Result<(), MemoryFault> {
+        let address = address % MEMORY_SIZE;
+        match self.find(address) {
+            Some(idx) => {
+                let (range, region) = &mut self.regions[idx];
+                if let Region::Device(device) = region {
+                    device
+                        .write(address - range.start, val)
+                        .map_err(|_| MemoryFault { address })?;
+                }
+            }
+            None => self.ram[address] = val,
+        }
+        Ok(())
+    }
+
+    /// Whole address space as the cpu sees it
+    pub fn dump(&self) -> Vec<u8> {
+        (0..MEMORY_SIZE).map(|address| self.read(address)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keeps the written bytes, reads return them inverted
+    struct Inverter(Vec<u8>);
+
+    impl MemoryDevice for Inverter {
+        fn read(&self, offset: usize) -> u8 {
+            !self.0[offset]
+        }
+
+        fn write(&mut self, offset: usize, val: u8) -> Result<(), DeviceError> {
+            self.0[offset] = val;
+            Ok(())
+        }
+    }
+
+    // status register that can't be written
+    struct Status;
+
+    impl MemoryDevice for Status {
+        fn read(&self, _offset: usize) -> u8 {
+            0x80
+        }
+
+        fn write(&mut self, _offset: usize, _val: u8) -> Result<(), DeviceError> {
+            Err(DeviceError)
+        }
+    }
+
+    #[test]
+    fn rom_ignores_writes() {
+        let mut bus = MemoryBus::new();
+        bus.write(0xf0000, 0x11).unwrap();
+        bus.map_rom(0xf0000, vec![0xaa, 0xbb]);
+        bus.write(0xf0000, 0x22).unwrap();
+        assert_eq!(bus.read(0xf0000), 0xaa);
+        assert_eq!(bus.read(0xf0001), 0xbb);
+        // ram right after the rom is writable
+        bus.write(0xf0002, 0x33).unwrap();
+        assert_eq!(bus.read(0xf0002), 0x33);
+    }
+
+    #[test]
+    fn device_gets_region_offsets() {
+        let mut bus = MemoryBus::new();
+        bus.map_device(0xb8000..0xb8002, Box::new(Inverter(vec![0; 2])));
+        bus.write(0xb8001, 0x0f).unwrap();
+        assert_eq!(bus.read(0xb8000), 0xff);
+        assert_eq!(bus.read(0xb8001), 0xf0);
+    }
+
+    #[test]
+    fn last_mapped_region_wins() {
+        let mut bus = MemoryBus::new();
+        bus.map_rom(0x1000, vec![1, 1]);
+        bus.map_rom(0x1001, vec![2]);
+        assert_eq!(bus.dump()[0x1000..0x1002], [1, 2]);
+    }
+
+    #[test]
+    fn address_wraps_at_one_megabyte() {
+        let mut bus = MemoryBus::new();
+        bus.write(MEMORY_SIZE + 5, 0x44).unwrap();
+        assert_eq!(bus.read(5), 0x44);
+    }
+
+    #[test]
+    fn refused_device_write_is_fault() {
+        let mut bus = MemoryBus::new();
+        bus.map_device(0x400..0x401, Box::new(Status));
+        assert_eq!(bus.write(0x400, 1).unwrap_err().address, 0x400);
+        assert_eq!(bus.read(0x400), 0x80);
+    }
+}
@@ -0,0 +1,38 @@
+use crate::ast::{Inst, OperandSize, Register};
+use crate::emulator::{Flags, SegmentedAddress, Step};
+
+/// Hooks into the execution, e.g. to build a profiler or a custom trace.
+/// Events of an instruction are recorded while it executes and replayed in the order
+/// they happened once it completes, between `before_instruction` and `after_instruction`,
+/// so the emulator state seen from a hook is the state after the instruction.
+/// A failed instruction reports `before_instruction` only.
+/// Instruction fetch isn't reported as a memory access.
+pub trait Observer {
+    fn before_instruction(&mut self, _address: SegmentedAddress, _inst: &Inst) {}
+
+    /// Isn't called if the instruction fails
+    fn after_instruction(&mut self, _step: &Step) {}
+
+    fn register_write(&mut self, _reg: Register, _from: u16, _to: u16) {}
+
+    fn memory_read(&mut self, _address: SegmentedAddress, _size: OperandSize, _val: u16) {}
+
+    fn memory_write(
+        &mut self,
+        _address: SegmentedAddress,
+        _size: OperandSize,
+        _from: u16,
+        _to: u16,
+    ) {
+    }
+
+    /// Reported once per instruction, right before `after_instruction`,
+    /// with the flags before and after it, intermediate changes aren't reported
+    fn flags_change(&mut self, _from: Flags, _to: Flags) {}
+
+    /// Reported before flags, cs and ip are pushed,
+    /// the return address is the one that is pushed
+    fn interrupt(&mut self, _vector: u8, _return_address: SegmentedAddress) {}
+}
+
+impl Observer for () {}